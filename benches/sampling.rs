@@ -0,0 +1,67 @@
+//! Compares the allocations of traced requests for sampled and unsampled spans.
+//!
+//! Run with `cargo bench --bench sampling`.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use actix_web::{test, web, App};
+use actix_web_opentelemetry::RequestTracing;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+
+const REQUESTS: usize = 10_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+async fn run(name: &str, sampler: Sampler) {
+    let provider = TracerProvider::builder().with_sampler(sampler).build();
+    let app = test::init_service(
+        App::new()
+            .wrap(RequestTracing::new().with_tracer_provider(provider))
+            .route("/users/{id}", web::get().to(|| async { "ok" })),
+    )
+    .await;
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        let req = test::TestRequest::get()
+            .uri("/users/42?page=1")
+            .insert_header(("user-agent", "bench"))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{name:>9}: {:>6.1} allocations/request, {:>8.2?}/request",
+        allocations as f64 / REQUESTS as f64,
+        elapsed / REQUESTS as u32,
+    );
+}
+
+fn main() {
+    actix_web::rt::System::new().block_on(async {
+        run("sampled", Sampler::AlwaysOn).await;
+        run("unsampled", Sampler::AlwaysOff).await;
+    });
+}
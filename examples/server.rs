@@ -3,10 +3,7 @@ use actix_web_opentelemetry::{PrometheusMetricsHandler, RequestMetrics, RequestT
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
-    metrics::{Aggregation, Instrument, SdkMeterProvider, Stream},
-    propagation::TraceContextPropagator,
-    trace::TracerProvider,
-    Resource,
+    metrics::SdkMeterProvider, propagation::TraceContextPropagator, trace::TracerProvider, Resource,
 };
 
 async fn index(_req: HttpRequest, _path: actix_web::web::Path<String>) -> &'static str {
@@ -44,19 +41,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let provider = SdkMeterProvider::builder()
             .with_reader(exporter)
             .with_resource(Resource::new([KeyValue::new("service.name", "my_app")]))
-            .with_view(
-                opentelemetry_sdk::metrics::new_view(
-                    Instrument::new().name("http.server.duration"),
-                    Stream::new().aggregation(Aggregation::ExplicitBucketHistogram {
-                        boundaries: vec![
-                            0.0, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5,
-                            5.0, 7.5, 10.0,
-                        ],
-                        record_min_max: true,
-                    }),
-                )
-                .unwrap(),
-            )
             .build();
         global::set_meter_provider(provider.clone());
 
@@ -66,7 +50,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     HttpServer::new(move || {
         let app = App::new()
             .wrap(RequestTracing::new())
-            .wrap(RequestMetrics::default())
+            .wrap(
+                RequestMetrics::builder()
+                    .with_duration_boundaries(vec![
+                        0.0, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0,
+                        7.5, 10.0,
+                    ])
+                    .build(),
+            )
             .service(web::resource("/users/{id}").to(index));
 
         #[cfg(feature = "metrics-prometheus")]
@@ -1,18 +1,18 @@
 use crate::{
-    middleware::get_scope,
-    util::{http_method_str, http_url},
+    middleware::{get_scope, route_formatter::RouteFormatter},
+    util::{client_span_status, client_trace_attributes, protocol_version},
 };
 use actix_http::{encoding::Decoder, BoxedPayloadStream, Error, Payload};
 use actix_web::{
     body::MessageBody,
     http::{
         self,
-        header::{HeaderName, HeaderValue},
+        header::{HeaderMap, HeaderName, HeaderValue},
     },
     web::Bytes,
 };
 use awc::{
-    error::SendRequestError,
+    error::{ConnectError, SendRequestError},
     http::header::{CONTENT_LENGTH, USER_AGENT},
     ClientRequest, ClientResponse,
 };
@@ -21,46 +21,84 @@ use opentelemetry::{
     global,
     propagation::Injector,
     trace::{SpanKind, Status, TraceContextExt, Tracer},
-    Context, KeyValue,
+    Context, InstrumentationScope, KeyValue,
 };
-use opentelemetry_semantic_conventions::trace::{
-    HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, MESSAGING_MESSAGE_BODY_SIZE, SERVER_ADDRESS,
-    SERVER_PORT, URL_FULL, USER_AGENT_ORIGINAL,
+use opentelemetry_semantic_conventions::{
+    attribute::{HTTP_REQUEST_BODY_SIZE, HTTP_RESPONSE_BODY_SIZE},
+    trace::{
+        ERROR_TYPE, HTTP_RESPONSE_STATUS_CODE, MESSAGING_MESSAGE_BODY_SIZE,
+        NETWORK_PROTOCOL_VERSION, USER_AGENT_ORIGINAL,
+    },
 };
 use serde::Serialize;
 use std::mem;
 use std::str::FromStr;
 use std::{
-    borrow::Cow,
     fmt::{self, Debug},
+    pin::Pin,
+    task::{self, ready, Poll},
 };
 
+const UPLOAD_PROGRESS_EVENT: &str = "http.request.body.progress";
+
 /// A wrapper for the actix-web [awc::ClientRequest].
+///
+/// Redirects followed by the [awc::Client] are not visible to this wrapper, so
+/// all hops are recorded in a single span with the originally requested
+/// `url.full`. To trace each hop separately, build the client with
+/// [`awc::ClientBuilder::disable_redirects`] and send a traced request for
+/// every `Location` you follow.
 pub struct InstrumentedClientRequest {
     cx: Context,
     attrs: Vec<KeyValue>,
-    span_namer: fn(&ClientRequest) -> String,
+    span_namer: Option<fn(&ClientRequest) -> String>,
+    route_formatter: Option<Box<dyn RouteFormatter + 'static>>,
+    scope: Option<InstrumentationScope>,
+    propagation_hosts: Option<Vec<String>>,
+    expected_status: Option<fn(http::StatusCode) -> bool>,
+    response_attributes: Option<fn(http::StatusCode, &HeaderMap) -> Vec<KeyValue>>,
+    default_server_port: bool,
+    upload_progress_interval: Option<u64>,
+    sort_query: bool,
     request: ClientRequest,
 }
 
 impl Debug for InstrumentedClientRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let span_namer = fmt::Pointer::fmt(&(self.span_namer as usize as *const ()), f);
+        let span_namer = self.span_namer.map(|namer| namer as *const ());
         f.debug_struct("InstrumentedClientRequest")
             .field("cx", &self.cx)
             .field("attrs", &self.attrs)
             .field("span_namer", &span_namer)
+            .field("route_formatter", &self.route_formatter)
+            .field("scope", &self.scope)
+            .field("propagation_hosts", &self.propagation_hosts)
+            .field("expected_status", &self.expected_status)
+            .field("response_attributes", &self.response_attributes)
+            .field("default_server_port", &self.default_server_port)
+            .field("upload_progress_interval", &self.upload_progress_interval)
+            .field("sort_query", &self.sort_query)
             .field("request", &self.request)
             .finish()
     }
 }
 
-fn default_span_namer(request: &ClientRequest) -> String {
-    format!(
-        "{} {}",
-        request.get_method(),
-        request.get_uri().host().unwrap_or_default()
-    )
+fn default_span_namer(
+    request: &ClientRequest,
+    route_formatter: Option<&(dyn RouteFormatter + 'static)>,
+) -> String {
+    match route_formatter {
+        Some(formatter) => format!(
+            "{} {}",
+            request.get_method(),
+            formatter.format(request.get_uri().path())
+        ),
+        None => format!(
+            "{} {}",
+            request.get_method(),
+            request.get_uri().host().unwrap_or_default()
+        ),
+    }
 }
 
 /// OpenTelemetry extensions for actix-web's [awc::Client].
@@ -118,7 +156,15 @@ impl ClientExt for ClientRequest {
         InstrumentedClientRequest {
             cx,
             attrs: Vec::with_capacity(8),
-            span_namer: default_span_namer,
+            span_namer: None,
+            route_formatter: None,
+            scope: None,
+            propagation_hosts: None,
+            expected_status: None,
+            response_attributes: None,
+            default_server_port: false,
+            upload_progress_interval: None,
+            sort_query: false,
             request: self,
         }
     }
@@ -129,7 +175,7 @@ type AwcResult = Result<ClientResponse<Decoder<Payload<BoxedPayloadStream>>>, Se
 impl InstrumentedClientRequest {
     /// Generate an [`awc::ClientResponse`] from a traced request with an empty body.
     pub async fn send(self) -> AwcResult {
-        self.trace_request(|request| request.send()).await
+        self.trace_request(|request, _| request.send()).await
     }
 
     /// Generate an [awc::ClientResponse] from a traced request with the given body.
@@ -137,66 +183,61 @@ impl InstrumentedClientRequest {
     where
         B: MessageBody + 'static,
     {
-        self.trace_request(|request| request.send_body(body)).await
+        self.trace_request(|request, _| request.send_body(body))
+            .await
     }
 
     /// Generate an [awc::ClientResponse] from a traced request with the given form
     /// body.
     pub async fn send_form<T: Serialize>(self, value: &T) -> AwcResult {
-        self.trace_request(|request| request.send_form(value)).await
+        self.trace_request(|request, _| request.send_form(value))
+            .await
     }
 
     /// Generate an [awc::ClientResponse] from a traced request with the given JSON
     /// body.
     pub async fn send_json<T: Serialize>(self, value: &T) -> AwcResult {
-        self.trace_request(|request| request.send_json(value)).await
+        self.trace_request(|request, _| request.send_json(value))
+            .await
     }
 
     /// Generate an [awc::ClientResponse] from a traced request with the given stream
     /// body.
+    ///
+    /// See [`InstrumentedClientRequest::with_upload_progress`] to record the
+    /// progress of the upload.
     pub async fn send_stream<S, E>(self, stream: S) -> AwcResult
     where
         S: Stream<Item = Result<Bytes, E>> + Unpin + 'static,
         E: std::error::Error + Into<Error> + 'static,
     {
-        self.trace_request(|request| request.send_stream(stream))
-            .await
+        let interval = self.upload_progress_interval;
+        self.trace_request(|request, cx| match interval {
+            Some(interval) => request.send_stream(ProgressStream {
+                stream,
+                cx: cx.clone(),
+                interval,
+                sent: 0,
+                next_event: interval,
+            }),
+            None => request.send_stream(stream),
+        })
+        .await
     }
 
     async fn trace_request<F, R>(mut self, f: F) -> AwcResult
     where
-        F: FnOnce(ClientRequest) -> R,
+        F: FnOnce(ClientRequest, &Context) -> R,
         R: Future<Output = AwcResult>,
     {
-        let tracer = global::tracer_with_scope(get_scope());
-
-        // Client attributes
-        // https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/http/http-spans.md#http-client
-        self.attrs.extend(
-            &mut [
-                KeyValue::new(
-                    SERVER_ADDRESS,
-                    self.request
-                        .get_uri()
-                        .host()
-                        .map(|u| Cow::Owned(u.to_string()))
-                        .unwrap_or(Cow::Borrowed("unknown")),
-                ),
-                KeyValue::new(
-                    HTTP_REQUEST_METHOD,
-                    http_method_str(self.request.get_method()),
-                ),
-                KeyValue::new(URL_FULL, http_url(self.request.get_uri())),
-            ]
-            .into_iter(),
-        );
-
-        if let Some(peer_port) = self.request.get_uri().port_u16() {
-            if peer_port != 80 && peer_port != 443 {
-                self.attrs
-                    .push(KeyValue::new(SERVER_PORT, peer_port as i64));
-            }
-        }
+        let tracer = global::tracer_with_scope(self.scope.take().unwrap_or_else(get_scope));
+
+        self.attrs.extend(client_trace_attributes(
+            self.request.get_method(),
+            self.request.get_uri(),
+            self.sort_query,
+            self.default_server_port,
+        ));
 
         if let Some(user_agent) = self
             .request
@@ -218,18 +259,34 @@ impl InstrumentedClientRequest {
         }
 
         let span = tracer
-            .span_builder((self.span_namer)(&self.request))
+            .span_builder(match self.span_namer {
+                Some(span_namer) => span_namer(&self.request),
+                None => default_span_namer(&self.request, self.route_formatter.as_deref()),
+            })
             .with_kind(SpanKind::Client)
             .with_attributes(mem::take(&mut self.attrs))
             .start_with_context(&tracer, &self.cx);
         let cx = self.cx.with_span(span);
 
-        global::get_text_map_propagator(|injector| {
-            injector.inject_context(&cx, &mut ActixClientCarrier::new(&mut self.request));
-        });
+        // `Option::is_none_or` requires Rust 1.82
+        let propagate = match &self.propagation_hosts {
+            Some(hosts) => self
+                .request
+                .get_uri()
+                .host()
+                .is_some_and(|host| hosts.iter().any(|h| h.eq_ignore_ascii_case(host))),
+            None => true,
+        };
+        if propagate {
+            global::get_text_map_propagator(|injector| {
+                injector.inject_context(&cx, &mut ActixClientCarrier::new(&mut self.request));
+            });
+        }
 
-        f(self.request)
-            .inspect_ok(|res| record_response(res, &cx))
+        f(self.request, &cx)
+            .inspect_ok(|res| {
+                record_response(res, &cx, self.expected_status, self.response_attributes)
+            })
             .inspect_err(|err| record_err(err, &cx))
             .await
     }
@@ -288,39 +345,364 @@ impl InstrumentedClientRequest {
         mut self,
         span_namer: fn(&ClientRequest) -> String,
     ) -> InstrumentedClientRequest {
-        self.span_namer = span_namer;
+        self.span_namer = Some(span_namer);
+        self
+    }
+
+    /// Name the span after the method and the formatted request path instead
+    /// of the method and host.
+    ///
+    /// Use a formatter that masks ids to keep span names low cardinality.
+    /// Has no effect if a custom span namer is set with
+    /// [`InstrumentedClientRequest::with_span_namer`].
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::{ClientExt, NumericIdFormatter};
+    /// use awc::{Client, error::SendRequestError};
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("http://localhost:8080/users/123")
+    ///         .trace_request()
+    ///         // span is named `GET /users/*`
+    ///         .with_route_formatter(NumericIdFormatter::new())
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_route_formatter<R>(mut self, route_formatter: R) -> InstrumentedClientRequest
+    where
+        R: RouteFormatter + 'static,
+    {
+        self.route_formatter = Some(Box::new(route_formatter));
+        self
+    }
+
+    /// Set the instrumentation scope of the tracer used for this request.
+    ///
+    /// Defaults to the `actix-web-opentelemetry` scope with the version of this
+    /// crate.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    /// use opentelemetry::InstrumentationScope;
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("http://localhost:8080")
+    ///         .trace_request()
+    ///         .with_scope(InstrumentationScope::builder("my-sdk").build())
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_scope(mut self, scope: InstrumentationScope) -> InstrumentedClientRequest {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Only propagate the trace context to the given hosts.
+    ///
+    /// Requests to other hosts, e.g. third party APIs, are still traced but
+    /// sent without propagation headers. By default the context is propagated
+    /// to every host.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("https://api.stripe.com/v1/charges")
+    ///         .trace_request()
+    ///         // no `traceparent` header is sent to stripe
+    ///         .with_propagation_hosts(["users.internal.svc", "orders.internal.svc"])
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_propagation_hosts(
+        mut self,
+        hosts: impl IntoIterator<Item = impl Into<String>>,
+    ) -> InstrumentedClientRequest {
+        self.propagation_hosts = Some(hosts.into_iter().map(Into::into).collect());
         self
     }
+
+    /// Treat the response statuses matched by `expected_status` as successful.
+    ///
+    /// By default all `4xx` and `5xx` responses set the span status to error.
+    /// Matched statuses leave the span status unset instead.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError, http::StatusCode};
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("http://localhost:8080")
+    ///         .trace_request()
+    ///         // missing resources are expected during existence checks
+    ///         .with_expected_status(|status| status == StatusCode::NOT_FOUND)
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_expected_status(
+        mut self,
+        expected_status: fn(http::StatusCode) -> bool,
+    ) -> InstrumentedClientRequest {
+        self.expected_status = Some(expected_status);
+        self
+    }
+
+    /// Add attributes derived from the response to the instrumented span.
+    ///
+    /// The function receives the response status and headers once they are
+    /// received, before the span ends.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    /// use opentelemetry::KeyValue;
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("http://localhost:8080")
+    ///         .trace_request()
+    ///         .with_response_attributes(|_status, headers| {
+    ///             headers
+    ///                 .get("x-upstream-region")
+    ///                 .and_then(|region| region.to_str().ok())
+    ///                 .map(|region| vec![KeyValue::new("upstream.region", region.to_string())])
+    ///                 .unwrap_or_default()
+    ///         })
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_response_attributes(
+        mut self,
+        response_attributes: fn(http::StatusCode, &HeaderMap) -> Vec<KeyValue>,
+    ) -> InstrumentedClientRequest {
+        self.response_attributes = Some(response_attributes);
+        self
+    }
+
+    /// Record `server.port` for default ports as well.
+    ///
+    /// By default the port is omitted when it is `80` or `443`. When enabled,
+    /// the port is always recorded, falling back to the default port of the
+    /// URI scheme if the URI has no explicit port.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("https://example.com")
+    ///         .trace_request()
+    ///         // records `server.port = 443`
+    ///         .with_default_server_port(true)
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_default_server_port(mut self, enabled: bool) -> InstrumentedClientRequest {
+        self.default_server_port = enabled;
+        self
+    }
+
+    /// Record upload progress of [`InstrumentedClientRequest::send_stream`]
+    /// bodies.
+    ///
+    /// Adds an `http.request.body.progress` event with the number of bytes sent
+    /// so far as `http.request.body.size` each time another `interval` bytes
+    /// have been sent, and records the total as `http.request.body.size` once
+    /// the stream ends. Disabled by default.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web::{error::PayloadError, web::Bytes};
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    /// use futures_util::stream;
+    ///
+    /// async fn upload(client: &Client, chunks: Vec<Bytes>) -> Result<(), SendRequestError> {
+    ///     let body = stream::iter(chunks.into_iter().map(Ok::<_, PayloadError>));
+    ///     let res = client.post("http://localhost:8080/upload")
+    ///         .trace_request()
+    ///         // add an event for every MiB uploaded
+    ///         .with_upload_progress(1024 * 1024)
+    ///         .send_stream(body)
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_upload_progress(mut self, interval: u64) -> InstrumentedClientRequest {
+        self.upload_progress_interval = Some(interval.max(1));
+        self
+    }
+
+    /// Sort the query parameters recorded in `url.full`.
+    ///
+    /// Requests with the same parameters in a different order, e.g. `?a=1&b=2`
+    /// and `?b=2&a=1`, are then recorded with the same `url.full`. The request
+    /// itself is sent unchanged. Disabled by default.
+    ///
+    /// Example:
+    /// ```
+    /// use actix_web_opentelemetry::ClientExt;
+    /// use awc::{Client, error::SendRequestError};
+    ///
+    /// async fn execute_request(client: &Client) -> Result<(), SendRequestError> {
+    ///     let res = client.get("http://localhost:8080/search?q=otel&page=2")
+    ///         .trace_request()
+    ///         // records `url.full = http://localhost:8080/search?page=2&q=otel`
+    ///         .with_sorted_query(true)
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_sorted_query(mut self, sort_query: bool) -> InstrumentedClientRequest {
+        self.sort_query = sort_query;
+        self
+    }
+}
+
+/// Request body stream recording upload progress on the client span.
+struct ProgressStream<S> {
+    stream: S,
+    cx: Context,
+    interval: u64,
+    sent: u64,
+    next_event: u64,
 }
 
-// convert http status code to span status following the rules described by the spec:
-// https://github.com/open-telemetry/opentelemetry-specification/blob/main/specification/trace/semantic_conventions/http.md#status
-fn convert_status(status: http::StatusCode) -> Status {
-    match status.as_u16() {
-        100..=399 => Status::Unset,
-        // since we are the client, we MUST treat 4xx as error
-        400..=599 => Status::error("Unexpected status code"),
-        code => Status::error(format!("Invalid HTTP status code {}", code)),
+impl<S, E> Stream for ProgressStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        task_cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let item = ready!(Pin::new(&mut this.stream).poll_next(task_cx));
+        match &item {
+            Some(Ok(bytes)) => {
+                this.sent += bytes.len() as u64;
+                if this.sent >= this.next_event {
+                    this.cx.span().add_event(
+                        UPLOAD_PROGRESS_EVENT,
+                        vec![KeyValue::new(HTTP_REQUEST_BODY_SIZE, this.sent as i64)],
+                    );
+                    this.next_event = (this.sent / this.interval + 1) * this.interval;
+                }
+            }
+            Some(Err(_)) => {}
+            None => {
+                this.cx
+                    .span()
+                    .set_attribute(KeyValue::new(HTTP_REQUEST_BODY_SIZE, this.sent as i64));
+            }
+        }
+        Poll::Ready(item)
     }
 }
 
-fn record_response<T>(response: &ClientResponse<T>, cx: &Context) {
+fn record_response<T>(
+    response: &ClientResponse<T>,
+    cx: &Context,
+    expected_status: Option<fn(http::StatusCode) -> bool>,
+    response_attributes: Option<fn(http::StatusCode, &HeaderMap) -> Vec<KeyValue>>,
+) {
     let span = cx.span();
-    let status = convert_status(response.status());
+    let status = if expected_status.is_some_and(|expected| expected(response.status())) {
+        Status::Unset
+    } else {
+        client_span_status(response.status().as_u16())
+    };
     span.set_status(status);
     span.set_attribute(KeyValue::new(
         HTTP_RESPONSE_STATUS_CODE,
         response.status().as_u16() as i64,
     ));
+    // the negotiated version is only known once the response is received
+    span.set_attribute(KeyValue::new(
+        NETWORK_PROTOCOL_VERSION,
+        protocol_version(response.version()),
+    ));
+    if let Some(content_length) = response.headers().get(CONTENT_LENGTH).and_then(|len| {
+        len.to_str()
+            .ok()
+            .and_then(|str_len| str_len.parse::<i64>().ok())
+    }) {
+        span.set_attribute(KeyValue::new(HTTP_RESPONSE_BODY_SIZE, content_length));
+    }
+    if let Some(response_attributes) = response_attributes {
+        span.set_attributes(response_attributes(response.status(), response.headers()));
+    }
     span.end();
 }
 
-fn record_err<T: fmt::Debug>(err: T, cx: &Context) {
+fn record_err(err: &SendRequestError, cx: &Context) {
     let span = cx.span();
+    span.set_attribute(KeyValue::new(ERROR_TYPE, error_type(err)));
     span.set_status(Status::error(format!("{:?}", err)));
     span.end();
 }
 
+// classify send errors for the `error.type` attribute:
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.23.0/docs/attributes-registry/error.md
+fn error_type(err: &SendRequestError) -> &'static str {
+    match err {
+        SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) => "timeout",
+        SendRequestError::Connect(
+            ConnectError::Resolver(_) | ConnectError::NoRecords | ConnectError::Unresolved,
+        ) => "dns",
+        SendRequestError::Connect(ConnectError::SslIsNotSupported) => "tls",
+        SendRequestError::Connect(ConnectError::Io(err))
+            if err.kind() == std::io::ErrorKind::ConnectionRefused =>
+        {
+            "connection_refused"
+        }
+        SendRequestError::Connect(_) => "connect",
+        SendRequestError::Url(_) => "invalid_url",
+        SendRequestError::Send(_) => "send",
+        SendRequestError::Response(_) => "invalid_response",
+        SendRequestError::Body(_) => "body",
+        _ => "_OTHER",
+    }
+}
+
 struct ActixClientCarrier<'a> {
     request: &'a mut ClientRequest,
 }
@@ -333,8 +715,38 @@ impl<'a> ActixClientCarrier<'a> {
 
 impl Injector for ActixClientCarrier<'_> {
     fn set(&mut self, key: &str, value: String) {
-        let header_name = HeaderName::from_str(key).expect("Must be header name");
-        let header_value = HeaderValue::from_str(&value).expect("Must be a header value");
-        self.request.headers_mut().insert(header_name, header_value);
+        // skip invalid headers produced by misconfigured propagators instead of
+        // failing the request
+        if let (Ok(header_name), Ok(header_value)) =
+            (HeaderName::from_str(key), HeaderValue::from_str(&value))
+        {
+            self.request.headers_mut().insert(header_name, header_value);
+        } else {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                name: "invalid_propagation_header",
+                target: env!("CARGO_PKG_NAME"),
+                name = "invalid_propagation_header",
+                key = key,
+                ""
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carrier_skips_invalid_headers() {
+        let mut request = awc::Client::default().get("http://localhost");
+        let mut carrier = ActixClientCarrier::new(&mut request);
+        carrier.set("invalid header", "value".to_string());
+        carrier.set("traceparent", "invalid\nvalue".to_string());
+        carrier.set("tracestate", "valid".to_string());
+
+        assert_eq!(request.headers().len(), 1);
+        assert_eq!(request.headers().get("tracestate").unwrap(), "valid");
     }
 }
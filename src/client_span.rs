@@ -0,0 +1,153 @@
+use crate::{
+    middleware::get_scope,
+    util::{client_span_status, client_trace_attributes},
+};
+use actix_web::http::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method, StatusCode, Uri,
+};
+use opentelemetry::{
+    global,
+    propagation::Injector,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue, StringValue,
+};
+use opentelemetry_semantic_conventions::trace::{ERROR_TYPE, HTTP_RESPONSE_STATUS_CODE};
+use std::borrow::Cow;
+
+/// Start a client span for a request sent by a custom http client.
+///
+/// The span follows the conventions of the awc and reqwest integrations: it is
+/// named `{method} {host}`, is a child of `cx` and records the method, url,
+/// server address and port of the request. Propagate it with
+/// [`ClientSpanGuard::inject_headers`] and record the outcome with
+/// [`ClientSpanGuard::record_response`] or [`ClientSpanGuard::record_error`].
+/// The span ends when the guard is dropped.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::http::{header::HeaderMap, Method, StatusCode, Uri};
+/// use actix_web_opentelemetry::start_client_span;
+/// use opentelemetry::Context;
+///
+/// let url = Uri::from_static("https://example.com/users");
+/// let span = start_client_span(&Context::current(), &Method::GET, &url);
+///
+/// let mut headers = HeaderMap::new();
+/// span.inject_headers(&mut headers);
+/// // send the request with a custom transport
+/// span.record_response(StatusCode::OK);
+/// ```
+pub fn start_client_span(cx: &Context, method: &Method, url: &Uri) -> ClientSpanGuard {
+    let tracer = global::tracer_with_scope(get_scope());
+    let span = tracer
+        .span_builder(format!("{} {}", method, url.host().unwrap_or_default()))
+        .with_kind(SpanKind::Client)
+        .with_attributes(client_trace_attributes(method, url, false, false))
+        .start_with_context(&tracer, cx);
+
+    ClientSpanGuard {
+        cx: cx.with_span(span),
+    }
+}
+
+/// A client span started with [`start_client_span`], ended when dropped.
+#[derive(Debug)]
+pub struct ClientSpanGuard {
+    cx: Context,
+}
+
+impl ClientSpanGuard {
+    /// The context of the client span, e.g. to record additional attributes or
+    /// to trace work done on behalf of the request.
+    pub fn context(&self) -> &Context {
+        &self.cx
+    }
+
+    /// Inject the client span context into the given carrier using the global
+    /// propagator.
+    pub fn inject(&self, injector: &mut dyn Injector) {
+        global::get_text_map_propagator(|propagator| propagator.inject_context(&self.cx, injector));
+    }
+
+    /// Inject the client span context into the given request headers using the
+    /// global propagator.
+    ///
+    /// Headers with invalid names or values are skipped, and logged with the
+    /// `tracing` feature.
+    pub fn inject_headers(&self, headers: &mut HeaderMap) {
+        self.inject(&mut HeaderCarrier { headers });
+    }
+
+    /// Record the status of the response, which sets an error span status for
+    /// `4xx` and `5xx` responses.
+    pub fn record_response(&self, status: StatusCode) {
+        let span = self.cx.span();
+        span.set_status(client_span_status(status.as_u16()));
+        span.set_attribute(KeyValue::new(
+            HTTP_RESPONSE_STATUS_CODE,
+            status.as_u16() as i64,
+        ));
+    }
+
+    /// Record a request that failed without a response.
+    ///
+    /// `error_type` is recorded as the `error.type` attribute and should be a
+    /// low cardinality classification of the error, e.g. `timeout`.
+    pub fn record_error(
+        &self,
+        error_type: impl Into<StringValue>,
+        description: impl Into<Cow<'static, str>>,
+    ) {
+        let span = self.cx.span();
+        span.set_attribute(KeyValue::new(ERROR_TYPE, error_type.into()));
+        span.set_status(Status::error(description));
+    }
+}
+
+impl Drop for ClientSpanGuard {
+    fn drop(&mut self) {
+        self.cx.span().end();
+    }
+}
+
+struct HeaderCarrier<'a> {
+    headers: &'a mut HeaderMap,
+}
+
+impl Injector for HeaderCarrier<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.headers.insert(name, value);
+        } else {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                name: "invalid_propagation_header",
+                target: env!("CARGO_PKG_NAME"),
+                name = "invalid_propagation_header",
+                key = key,
+                ""
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carrier_skips_invalid_headers() {
+        let mut headers = HeaderMap::new();
+        let mut carrier = HeaderCarrier {
+            headers: &mut headers,
+        };
+        carrier.set("invalid header", "value".to_string());
+        carrier.set("traceparent", "invalid\nvalue".to_string());
+        carrier.set("tracestate", "valid".to_string());
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("tracestate").unwrap(), "valid");
+    }
+}
@@ -8,13 +8,27 @@
 //!
 //! * Client requests can be traced by using the [`ClientExt::trace_request`] method.
 //!
+//! The `reqwest` feature allows you to instrument client requests made by the [reqwest] crate
+//! in the same way, using the [`ReqwestClientExt::trace_request`] method.
+//!
 //! The `metrics` feature allows you to expose request metrics to [Prometheus].
 //!
 //! * Metrics can be tracked using the [`RequestMetrics`] middleware.
 //!
+//! The `tracing` feature makes the [`RequestTracing`] middleware enter a [`tracing`]
+//! span with `trace_id` and `span_id` fields for each request, so logs emitted
+//! with the `tracing` crate can be correlated with traces. The span is entered
+//! while the inner service is called and whenever the response future is polled,
+//! independently of the `sync-middleware` feature, which only controls whether the
+//! OpenTelemetry context is attached during the inner service call. With
+//! [`RequestTracing::with_tracing_compat`] the request spans themselves are
+//! created with `tracing` for apps exporting spans through `tracing-opentelemetry`.
+//!
 //! [OpenTelemetry]: https://opentelemetry.io
 //! [Actix Web]: https://actix.rs
 //! [awc]: https://docs.rs/awc
+//! [`tracing`]: https://docs.rs/tracing
+//! [reqwest]: https://docs.rs/reqwest
 //! [Prometheus]: https://prometheus.io
 //!
 //! ### Client Request Examples:
@@ -146,25 +160,47 @@
 
 #[cfg(feature = "awc")]
 mod client;
+mod client_span;
 mod middleware;
+#[cfg(feature = "reqwest")]
+mod reqwest_client;
 mod util;
 
 #[cfg(feature = "awc")]
 #[cfg_attr(docsrs, doc(cfg(feature = "awc")))]
 pub use client::{ClientExt, InstrumentedClientRequest};
 
+#[cfg(feature = "reqwest")]
+#[cfg_attr(docsrs, doc(cfg(feature = "reqwest")))]
+pub use reqwest_client::{InstrumentedReqwestRequest, ReqwestClientExt};
+
 #[cfg(feature = "metrics-prometheus")]
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics-prometheus")))]
 pub use middleware::metrics::prometheus::PrometheusMetricsHandler;
 #[cfg(feature = "metrics")]
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
-pub use middleware::metrics::{RequestMetrics, RequestMetricsBuilder, RequestMetricsMiddleware};
+pub use middleware::metrics::{
+    MeteredBody, RequestMetrics, RequestMetricsBuilder, RequestMetricsMiddleware,
+    RequestQueueStart, UncompressedResponseSize,
+};
 #[cfg(feature = "metrics")]
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
 pub use util::metrics_attributes_from_request;
 
+#[cfg(feature = "regex")]
+#[cfg_attr(docsrs, doc(cfg(feature = "regex")))]
+pub use middleware::route_formatter::RegexRouteFormatter;
 
 pub use {
-    middleware::route_formatter::RouteFormatter,
-    middleware::trace::{RequestTracing, RequestTracingMiddleware},
+    client_span::{start_client_span, ClientSpanGuard},
+    middleware::filter::RequestFilter,
+    middleware::route_formatter::{
+        BuiltinRouteFormatter, ChainedRouteFormatter, NumericIdFormatter, RouteFormatter,
+        TokenWildcardFormatter, UlidWildcardFormatter, UuidWildcardFormatter,
+    },
+    middleware::trace::{
+        DeferSpanEnd, RequestTracing, RequestTracingMiddleware, ServerAddressSource, TlsInfo,
+        UnmatchedRouteStrategy,
+    },
+    util::trace_attributes_from_request,
 };
@@ -0,0 +1,78 @@
+//! # Request Filter
+//!
+//! Select the requests the middlewares instrument.
+
+use actix_web::dev::ServiceRequest;
+use std::{fmt, sync::Arc};
+
+/// Predicate selecting the requests to trace or record metrics for.
+///
+/// A single filter can be shared by the [`RequestTracing`] and
+/// `RequestMetrics` middlewares, so both skip the same requests. Requests for
+/// which the filter returns `false` are passed straight through to the inner
+/// service.
+///
+/// [`RequestTracing`]: crate::RequestTracing
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{RequestFilter, RequestTracing};
+///
+/// let filter = RequestFilter::exclude_paths(["/healthz", "/metrics"]);
+///
+/// let tracing = RequestTracing::new().with_request_filter(filter.clone());
+/// # #[cfg(feature = "metrics")]
+/// let metrics = actix_web_opentelemetry::RequestMetrics::builder()
+///     .with_request_filter(filter)
+///     .build();
+/// # use actix_web::test::TestRequest;
+/// # let filter = RequestFilter::exclude_paths(["/healthz"]);
+/// # assert!(!filter.matches(&TestRequest::with_uri("/healthz").to_srv_request()));
+/// # assert!(filter.matches(&TestRequest::with_uri("/users").to_srv_request()));
+/// # let filter = RequestFilter::only_paths(["/api"]);
+/// # assert!(filter.matches(&TestRequest::with_uri("/api").to_srv_request()));
+/// # assert!(!filter.matches(&TestRequest::with_uri("/healthz").to_srv_request()));
+/// ```
+#[derive(Clone)]
+pub struct RequestFilter(Arc<dyn Fn(&ServiceRequest) -> bool + Send + Sync + 'static>);
+
+impl RequestFilter {
+    /// Select the requests for which the given predicate returns `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::http::Method;
+    /// use actix_web_opentelemetry::RequestFilter;
+    ///
+    /// // skip CORS preflight requests
+    /// let filter = RequestFilter::new(|req| req.method() != Method::OPTIONS);
+    /// ```
+    pub fn new(filter: impl Fn(&ServiceRequest) -> bool + Send + Sync + 'static) -> Self {
+        RequestFilter(Arc::new(filter))
+    }
+
+    /// Select all requests except those to the given paths.
+    pub fn exclude_paths(paths: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let paths = paths.into_iter().map(Into::into).collect::<Vec<String>>();
+        RequestFilter::new(move |req| !paths.iter().any(|path| path == req.path()))
+    }
+
+    /// Select only the requests to the given paths.
+    pub fn only_paths(paths: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let paths = paths.into_iter().map(Into::into).collect::<Vec<String>>();
+        RequestFilter::new(move |req| paths.iter().any(|path| path == req.path()))
+    }
+
+    /// Whether the given request is selected.
+    pub fn matches(&self, req: &ServiceRequest) -> bool {
+        (self.0)(req)
+    }
+}
+
+impl fmt::Debug for RequestFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestFilter")
+    }
+}
@@ -1,31 +1,53 @@
 //! # Metrics Middleware
 
 use actix_http::{
-    body::{BodySize, MessageBody},
+    body::{BodySize, EitherBody, MessageBody},
     header::CONTENT_LENGTH,
 };
-use actix_web::dev;
+use actix_web::{dev, http::Method, web::Bytes, HttpMessage as _};
 use futures_util::future::{self, FutureExt as _, LocalBoxFuture};
 use opentelemetry::{
     global,
-    metrics::{Histogram, Meter, MeterProvider, UpDownCounter},
-    KeyValue,
+    metrics::{Counter, Histogram, Meter, MeterProvider, UpDownCounter},
+    trace::TraceContextExt as _,
+    Context as OtelContext, InstrumentationScope, Key, KeyValue,
 };
+use pin_project_lite::pin_project;
 use std::borrow::Cow;
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Instant, SystemTime},
+};
 
-use super::get_scope;
+use super::{filter::RequestFilter, get_scope, UNMATCHED_ROUTE};
 use crate::util::metrics_attributes_from_request;
 use crate::RouteFormatter;
 
 // Follows the experimental semantic conventions for HTTP metrics:
 // https://github.com/open-telemetry/opentelemetry-specification/blob/main/specification/metrics/semantic_conventions/http-metrics.md
-use opentelemetry_semantic_conventions::trace::HTTP_RESPONSE_STATUS_CODE;
+use opentelemetry_semantic_conventions::trace::{
+    ERROR_TYPE, HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE,
+};
 
 const HTTP_SERVER_DURATION: &str = "http.server.duration";
 const HTTP_SERVER_ACTIVE_REQUESTS: &str = "http.server.active_requests";
 const HTTP_SERVER_REQUEST_SIZE: &str = "http.server.request.size";
 const HTTP_SERVER_RESPONSE_SIZE: &str = "http.server.response.size";
+const HTTP_SERVER_REQUEST_COUNT: &str = "http.server.request.count";
+const HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE: &str = "http.server.response.time_to_first_byte";
+const HTTP_SERVER_QUEUE_DURATION: &str = "http.server.queue.duration";
+const HTTP_SERVER_RESPONSE_UNCOMPRESSED_SIZE: &str = "http.server.response.uncompressed_size";
+const HTTP_SERVER_RESPONSE_COMPRESSION_RATIO: &str = "http.server.response.compression_ratio";
+// Stable names from semantic conventions v1.23+:
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.23.0/docs/http/http-metrics.md#http-server
+const HTTP_SERVER_REQUEST_DURATION: &str = "http.server.request.duration";
+const HTTP_SERVER_REQUEST_BODY_SIZE: &str = "http.server.request.body.size";
+const HTTP_SERVER_RESPONSE_BODY_SIZE: &str = "http.server.response.body.size";
+const HTTP_RESPONSE_STATUS_CODE_CLASS: &str = "http.response.status_code_class";
+const TRACE_ID: &str = "trace_id";
 
 /// Records http server metrics
 ///
@@ -38,16 +60,57 @@ struct Metrics {
     http_server_active_requests: UpDownCounter<i64>,
     http_server_request_size: Histogram<u64>,
     http_server_response_size: Histogram<u64>,
+    http_server_request_count: Option<Counter<u64>>,
+    http_server_time_to_first_byte: Option<Histogram<f64>>,
+    /// Durations of read and write requests, see [`is_read_method`]
+    http_server_duration_by_method: Option<(Histogram<f64>, Histogram<f64>)>,
+    http_server_queue_duration: Option<Histogram<f64>>,
+    /// Uncompressed response sizes and compression ratios
+    http_server_uncompressed_response_size: Option<(Histogram<u64>, Histogram<f64>)>,
 }
 
+/// Default bucket boundaries (in bytes) of the request and response size histograms
+const DEFAULT_SIZE_BOUNDARIES: [f64; 6] = [
+    100.0,
+    1_000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+];
+
+/// Default bucket boundaries of the compression ratio histogram
+const DEFAULT_COMPRESSION_RATIO_BOUNDARIES: [f64; 8] = [1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+
 impl Metrics {
     /// Create a new [`RequestMetrics`]
-    fn new(meter: Meter) -> Self {
-        let http_server_duration = meter
-            .f64_histogram(HTTP_SERVER_DURATION)
+    fn new(
+        meter: Meter,
+        boundaries: HistogramBoundaries,
+        stable_semconv: bool,
+        instruments: OptionalInstruments,
+    ) -> Self {
+        let (duration_name, request_size_name, response_size_name) = if stable_semconv {
+            (
+                HTTP_SERVER_REQUEST_DURATION,
+                HTTP_SERVER_REQUEST_BODY_SIZE,
+                HTTP_SERVER_RESPONSE_BODY_SIZE,
+            )
+        } else {
+            (
+                HTTP_SERVER_DURATION,
+                HTTP_SERVER_REQUEST_SIZE,
+                HTTP_SERVER_RESPONSE_SIZE,
+            )
+        };
+
+        let mut http_server_duration = meter
+            .f64_histogram(duration_name)
             .with_description("Measures the duration of inbound HTTP requests.")
-            .with_unit("s")
-            .build();
+            .with_unit("s");
+        if let Some(boundaries) = &boundaries.duration {
+            http_server_duration = http_server_duration.with_boundaries(boundaries.clone());
+        }
 
         let http_server_active_requests = meter
             .i64_up_down_counter(HTTP_SERVER_ACTIVE_REQUESTS)
@@ -57,32 +120,156 @@ impl Metrics {
             .build();
 
         let http_server_request_size = meter
-            .u64_histogram(HTTP_SERVER_REQUEST_SIZE)
+            .u64_histogram(request_size_name)
             .with_description("Measures the size of HTTP request messages (compressed).")
-            .with_unit("By")
-            .build();
+            .with_unit("By");
+        let http_server_request_size = http_server_request_size.with_boundaries(
+            boundaries
+                .request_size
+                .unwrap_or_else(|| DEFAULT_SIZE_BOUNDARIES.to_vec()),
+        );
 
         let http_server_response_size = meter
-            .u64_histogram(HTTP_SERVER_RESPONSE_SIZE)
+            .u64_histogram(response_size_name)
             .with_description("Measures the size of HTTP response messages (compressed).")
-            .with_unit("By")
-            .build();
+            .with_unit("By");
+        let response_size_boundaries = boundaries
+            .response_size
+            .unwrap_or_else(|| DEFAULT_SIZE_BOUNDARIES.to_vec());
+        let http_server_uncompressed_response_size =
+            instruments.uncompressed_response_size.then(|| {
+                let size = meter
+                    .u64_histogram(HTTP_SERVER_RESPONSE_UNCOMPRESSED_SIZE)
+                    .with_description("Measures the size of HTTP response messages (uncompressed).")
+                    .with_unit("By")
+                    .with_boundaries(response_size_boundaries.clone())
+                    .build();
+                let ratio = meter
+                    .f64_histogram(HTTP_SERVER_RESPONSE_COMPRESSION_RATIO)
+                    .with_description(
+                        "Measures the ratio of uncompressed to compressed HTTP response sizes.",
+                    )
+                    .with_boundaries(DEFAULT_COMPRESSION_RATIO_BOUNDARIES.to_vec())
+                    .build();
+                (size, ratio)
+            });
+        let http_server_response_size =
+            http_server_response_size.with_boundaries(response_size_boundaries);
+
+        let http_server_request_count = instruments.request_count.then(|| {
+            meter
+                .u64_counter(HTTP_SERVER_REQUEST_COUNT)
+                .with_description("Counts the number of inbound HTTP requests.")
+                .build()
+        });
+
+        let http_server_time_to_first_byte = instruments.time_to_first_byte.then(|| {
+            let mut histogram = meter
+                .f64_histogram(HTTP_SERVER_RESPONSE_TIME_TO_FIRST_BYTE)
+                .with_description(
+                    "Measures the time until the first chunk of inbound HTTP request responses is sent.",
+                )
+                .with_unit("s");
+            if let Some(boundaries) = &boundaries.duration {
+                histogram = histogram.with_boundaries(boundaries.clone());
+            }
+            histogram.build()
+        });
+
+        let http_server_duration_by_method = instruments.method_durations.then(|| {
+            let histogram = |family: &str| {
+                let mut histogram = meter
+                    .f64_histogram(format!("{duration_name}.{family}"))
+                    .with_description(format!(
+                        "Measures the duration of inbound HTTP {family} requests."
+                    ))
+                    .with_unit("s");
+                if let Some(boundaries) = &boundaries.duration {
+                    histogram = histogram.with_boundaries(boundaries.clone());
+                }
+                histogram.build()
+            };
+            (histogram("read"), histogram("write"))
+        });
+
+        let http_server_queue_duration = instruments.queue_duration.then(|| {
+            let mut histogram = meter
+                .f64_histogram(HTTP_SERVER_QUEUE_DURATION)
+                .with_description(
+                    "Measures the time inbound HTTP requests wait before being handled.",
+                )
+                .with_unit("s");
+            if let Some(boundaries) = &boundaries.duration {
+                histogram = histogram.with_boundaries(boundaries.clone());
+            }
+            histogram.build()
+        });
 
         Metrics {
             http_server_active_requests,
-            http_server_duration,
-            http_server_request_size,
-            http_server_response_size,
+            http_server_duration_by_method,
+            http_server_queue_duration,
+            http_server_request_count,
+            http_server_time_to_first_byte,
+            http_server_uncompressed_response_size,
+            http_server_duration: http_server_duration.build(),
+            http_server_request_size: http_server_request_size.build(),
+            http_server_response_size: http_server_response_size.build(),
         }
     }
 }
 
+/// Explicit bucket boundaries for the http server histograms
+#[derive(Clone, Debug, Default)]
+struct HistogramBoundaries {
+    duration: Option<Vec<f64>>,
+    request_size: Option<Vec<f64>>,
+    response_size: Option<Vec<f64>>,
+}
+
+/// Instruments only created when enabled on the [`RequestMetricsBuilder`]
+#[derive(Clone, Copy, Debug, Default)]
+struct OptionalInstruments {
+    request_count: bool,
+    time_to_first_byte: bool,
+    method_durations: bool,
+    queue_duration: bool,
+    uncompressed_response_size: bool,
+}
+
+type MetricAttrsFromReq = fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>;
+
+/// Meter provider set with [`RequestMetricsBuilder::with_meter_provider`]
+#[derive(Clone)]
+struct SharedMeterProvider(Arc<dyn MeterProvider + Send + Sync + 'static>);
+
+impl fmt::Debug for SharedMeterProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedMeterProvider")
+    }
+}
+
 /// Builder for [RequestMetrics]
 #[derive(Clone, Debug, Default)]
 pub struct RequestMetricsBuilder {
     route_formatter: Option<Arc<dyn RouteFormatter + Send + Sync + 'static>>,
+    meter_provider: Option<SharedMeterProvider>,
     meter: Option<Meter>,
-    metric_attrs_from_req: Option<fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>>
+    scope: Option<InstrumentationScope>,
+    metric_attrs_from_req: Option<MetricAttrsFromReq>,
+    route_allowlist: Vec<String>,
+    unmatched_route_label: Option<Cow<'static, str>>,
+    skip_unmatched_routes: bool,
+    boundaries: HistogramBoundaries,
+    status_code_class: bool,
+    stable_semconv: bool,
+    active_requests_attributes: Option<Vec<Key>>,
+    minimal_active_requests_attributes: bool,
+    size_attributes: Option<Vec<Key>>,
+    measured_response_body: bool,
+    trace_id_attribute: bool,
+    instruments: OptionalInstruments,
+    filter: Option<RequestFilter>,
 }
 
 impl RequestMetricsBuilder {
@@ -101,31 +288,493 @@ impl RequestMetricsBuilder {
     }
 
     /// Set the meter provider this middleware should use to construct meters
-    pub fn with_meter_provider(mut self, meter_provider: impl MeterProvider) -> Self {
-        self.meter = Some(meter_provider.meter_with_scope(get_scope()));
+    pub fn with_meter_provider(
+        mut self,
+        meter_provider: impl MeterProvider + Send + Sync + 'static,
+    ) -> Self {
+        self.meter_provider = Some(SharedMeterProvider(Arc::new(meter_provider)));
+        self.meter = None;
+        self
+    }
+
+    /// Set the instrumentation scope of the meter this middleware creates
+    ///
+    /// Defaults to the `actix-web-opentelemetry` scope with the version of this
+    /// crate. Ignored if a meter is set with [`RequestMetricsBuilder::with_meter`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    /// use opentelemetry::InstrumentationScope;
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_scope(InstrumentationScope::builder("my-sdk").with_version("1.0.0").build())
+    ///     .build();
+    /// ```
+    pub fn with_scope(mut self, scope: InstrumentationScope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Set the meter this middleware should use to create its instruments
+    ///
+    /// Useful to share one meter between many middlewares, e.g. when wrapping
+    /// several apps or scopes. Instruments are identified by their name within
+    /// a meter provider, so middlewares built from the same meter record into
+    /// the same instruments.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    /// use opentelemetry::global;
+    ///
+    /// let meter = global::meter("my-service");
+    ///
+    /// let api_metrics = RequestMetrics::builder().with_meter(meter.clone()).build();
+    /// let admin_metrics = RequestMetrics::builder().with_meter(meter).build();
+    /// ```
+    pub fn with_meter(mut self, meter: Meter) -> Self {
+        self.meter = Some(meter);
+        self.meter_provider = None;
         self
     }
 
     /// Set a metric attrs function that the middleware will use to create metric attributes
-    pub fn with_metric_attrs_from_req(mut self, metric_attrs_from_req: fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>) -> Self {
+    pub fn with_metric_attrs_from_req(
+        mut self,
+        metric_attrs_from_req: fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>,
+    ) -> Self {
         self.metric_attrs_from_req = Some(metric_attrs_from_req);
         self
     }
 
+    /// Only record metrics for requests for which the given predicate returns `true`
+    ///
+    /// Filtered requests are passed straight through to the inner service without
+    /// updating any instrument, e.g. to exclude health checks or the metrics
+    /// endpoint itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_filter(|req| !matches!(req.path(), "/metrics" | "/healthz"))
+    ///     .build();
+    /// ```
+    pub fn with_filter(mut self, filter: fn(&dev::ServiceRequest) -> bool) -> Self {
+        self.filter = Some(RequestFilter::new(filter));
+        self
+    }
+
+    /// Only record metrics for requests selected by the given filter
+    ///
+    /// Like [`RequestMetricsBuilder::with_filter`], but the filter can be shared
+    /// with the [`RequestTracing`] middleware.
+    ///
+    /// [`RequestTracing`]: crate::RequestTracing
+    pub fn with_request_filter(mut self, filter: RequestFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Only record metrics for requests matching one of the given route patterns
+    ///
+    /// Patterns are compared against the unformatted [match pattern], e.g.
+    /// `/users/{id}`. Requests for other routes are not recorded at all. An
+    /// empty list records every request.
+    ///
+    /// [match pattern]: actix_web::HttpRequest::match_pattern
+    pub fn with_route_allowlist(mut self, route_allowlist: Vec<String>) -> Self {
+        self.route_allowlist = route_allowlist;
+        self
+    }
+
+    /// Set the `http.route` recorded for requests that matched no route
+    ///
+    /// Defaults to `default`.
+    pub fn with_unmatched_route_label(mut self, label: impl Into<Cow<'static, str>>) -> Self {
+        self.unmatched_route_label = Some(label.into());
+        self
+    }
+
+    /// Whether to record metrics for requests that matched no route
+    ///
+    /// Enabled by default. Disable it to keep requests for random paths, e.g.
+    /// from scanners, out of the metrics.
+    pub fn with_unmatched_routes(mut self, unmatched_routes: bool) -> Self {
+        self.skip_unmatched_routes = !unmatched_routes;
+        self
+    }
+
+    /// Record the response status code class (e.g. `2xx`) as
+    /// `http.response.status_code_class` instead of the exact
+    /// `http.response.status_code`, to reduce metric cardinality
+    pub fn with_status_code_class(mut self, status_code_class: bool) -> Self {
+        self.status_code_class = status_code_class;
+        self
+    }
+
+    /// Use the stable http server metric names from semantic conventions v1.23+
+    ///
+    /// Instruments are named `http.server.request.duration`,
+    /// `http.server.request.body.size` and `http.server.response.body.size`
+    /// instead of `http.server.duration`, `http.server.request.size` and
+    /// `http.server.response.size`. Units are unchanged.
+    pub fn with_stable_semconv(mut self, stable_semconv: bool) -> Self {
+        self.stable_semconv = stable_semconv;
+        self
+    }
+
+    /// Only record the given attributes on the `http.server.active_requests` counter
+    ///
+    /// Each attribute combination of an up down counter is retained for the
+    /// lifetime of the meter provider, so a small set such as method and route
+    /// keeps its memory use bounded. Other instruments are unaffected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    /// use opentelemetry_semantic_conventions::trace::{HTTP_REQUEST_METHOD, HTTP_ROUTE};
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_active_requests_attributes([HTTP_REQUEST_METHOD, HTTP_ROUTE])
+    ///     .build();
+    /// ```
+    pub fn with_active_requests_attributes(
+        mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Self {
+        self.active_requests_attributes = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only record the request method and route on the
+    /// `http.server.active_requests` counter
+    ///
+    /// Shorthand for [`RequestMetricsBuilder::with_active_requests_attributes`]
+    /// with `http.request.method` and `http.route`, which takes precedence if
+    /// both are set. When disabled, the default, the counter records all request
+    /// attributes. Response attributes such as the status code are never
+    /// recorded on the counter either way, as requests are counted before the
+    /// response is known.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_minimal_active_requests_attributes(true)
+    ///     .build();
+    /// # let registry = prometheus::Registry::new();
+    /// # let exporter = opentelemetry_prometheus::exporter()
+    /// #     .with_registry(registry.clone())
+    /// #     .build()
+    /// #     .unwrap();
+    /// # let provider = opentelemetry_sdk::metrics::SdkMeterProvider::builder()
+    /// #     .with_reader(exporter)
+    /// #     .build();
+    /// # let metrics = RequestMetrics::builder()
+    /// #     .with_minimal_active_requests_attributes(true)
+    /// #     .build_with_provider(provider.clone());
+    /// # let app = actix_web::App::new().wrap(metrics);
+    /// # actix_web::rt::System::new().block_on(async {
+    /// #     let app = actix_web::test::init_service(app).await;
+    /// #     let req = actix_web::test::TestRequest::get().to_request();
+    /// #     actix_web::test::call_service(&app, req).await;
+    /// # });
+    /// # let active = registry
+    /// #     .gather()
+    /// #     .into_iter()
+    /// #     .find(|family| family.get_name() == "http_server_active_requests")
+    /// #     .unwrap();
+    /// # assert!(!active.get_metric().is_empty());
+    /// # for metric in active.get_metric() {
+    /// #     let labels = metric.get_label().iter().map(|l| l.get_name()).collect::<Vec<_>>();
+    /// #     assert!(labels.contains(&"http_request_method"));
+    /// #     assert!(labels.contains(&"http_route"));
+    /// #     assert!(!labels.contains(&"http_response_status_code"));
+    /// #     assert!(!labels.contains(&"server_address"));
+    /// # }
+    /// ```
+    pub fn with_minimal_active_requests_attributes(mut self, minimal: bool) -> Self {
+        self.minimal_active_requests_attributes = minimal;
+        self
+    }
+
+    /// Only record the given attributes on the request and response size histograms
+    ///
+    /// The response status code is only recorded if listed. Other instruments
+    /// are unaffected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    /// use opentelemetry_semantic_conventions::trace::{HTTP_REQUEST_METHOD, HTTP_ROUTE};
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_size_attributes([HTTP_ROUTE, HTTP_REQUEST_METHOD])
+    ///     .build();
+    /// ```
+    pub fn with_size_attributes(mut self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Self {
+        self.size_attributes = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Count the bytes of streamed response bodies for `http.server.response.size`
+    ///
+    /// By default only responses with a known size are measured and streamed
+    /// responses are recorded as `0`. When enabled, streamed bodies are wrapped
+    /// to count their bytes, which are recorded once the body is complete.
+    pub fn with_measured_response_body(mut self, measured_response_body: bool) -> Self {
+        self.measured_response_body = measured_response_body;
+        self
+    }
+
+    /// Record the trace id of sampled requests as a `trace_id` attribute on
+    /// the `http.server.duration` histogram
+    ///
+    /// The trace id is read from the current context, so [`RequestTracing`]
+    /// must wrap this middleware, i.e. be registered after it. This is a
+    /// stand-in for exemplars, which the SDK does not support yet: every
+    /// sampled request creates a new series, so only enable it with low
+    /// sampling rates or a backend that drops the attribute on ingestion.
+    ///
+    /// [`RequestTracing`]: crate::RequestTracing
+    pub fn with_trace_id_attribute(mut self, trace_id_attribute: bool) -> Self {
+        self.trace_id_attribute = trace_id_attribute;
+        self
+    }
+
+    /// Count requests with the `http.server.request.count` counter
+    ///
+    /// The counter has the same attributes as `http.server.duration`, including
+    /// the response status code, so error rates can be computed without relying
+    /// on the histogram `_count` series.
+    pub fn with_request_count(mut self, request_count: bool) -> Self {
+        self.instruments.request_count = request_count;
+        self
+    }
+
+    /// Record the time until the first response body chunk is sent with the
+    /// `http.server.response.time_to_first_byte` histogram
+    ///
+    /// Unlike `http.server.duration`, which ends once the handler returns the
+    /// response, this includes the time streamed bodies take to produce their
+    /// first chunk. The histogram has the same attributes and boundaries as
+    /// `http.server.duration`. Disabled by default as it wraps every response
+    /// body.
+    pub fn with_time_to_first_byte(mut self, time_to_first_byte: bool) -> Self {
+        self.instruments.time_to_first_byte = time_to_first_byte;
+        self
+    }
+
+    /// Also record request durations in separate `http.server.duration.read`
+    /// and `http.server.duration.write` histograms
+    ///
+    /// `GET`, `HEAD`, `OPTIONS` and `TRACE` requests are reads, all other
+    /// methods are writes. Meant for monitoring setups that cannot filter the
+    /// `http.server.duration` histogram by the request method attribute. The
+    /// histograms have the same attributes and boundaries as
+    /// `http.server.duration`, or `http.server.request.duration` with
+    /// [`RequestMetricsBuilder::with_stable_semconv`]. Disabled by default.
+    pub fn with_method_durations(mut self, method_durations: bool) -> Self {
+        self.instruments.method_durations = method_durations;
+        self
+    }
+
+    /// Record the time requests wait before reaching this middleware with the
+    /// `http.server.queue.duration` histogram
+    ///
+    /// Actix does not expose when a request was accepted, so the wait starts at
+    /// the [`RequestQueueStart`] set in the request extensions, e.g. by a
+    /// middleware registered before any slow ones. Requests without it are not
+    /// recorded. The histogram has the attributes of `http.server.duration`
+    /// except for the response status, and the same boundaries. Disabled by
+    /// default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{dev::Service as _, App, HttpMessage as _};
+    /// use actix_web_opentelemetry::{RequestMetrics, RequestQueueStart};
+    /// use std::time::Instant;
+    ///
+    /// let app = App::new()
+    ///     .wrap(RequestMetrics::builder().with_queue_duration(true).build())
+    ///     .wrap_fn(|req, srv| {
+    ///         req.extensions_mut().insert(RequestQueueStart(Instant::now()));
+    ///         srv.call(req)
+    ///     });
+    /// ```
+    pub fn with_queue_duration(mut self, queue_duration: bool) -> Self {
+        self.instruments.queue_duration = queue_duration;
+        self
+    }
+
+    /// Record the uncompressed size of compressed responses with the
+    /// `http.server.response.uncompressed_size` histogram
+    ///
+    /// `http.server.response.size` records the body as seen by this
+    /// middleware: registered after [`Compress`], i.e. wrapping it, that is the
+    /// compressed body, which is streamed and only measured with
+    /// [`RequestMetricsBuilder::with_measured_response_body`]. Registered
+    /// before it, the uncompressed body is recorded instead.
+    ///
+    /// The compressed body does not know its uncompressed size, so it is read
+    /// from the [`UncompressedResponseSize`] set in the response extensions,
+    /// e.g. by a middleware wrapped by [`Compress`]. Responses without it are
+    /// not recorded. If the compressed size is known as well, their ratio is
+    /// recorded with the `http.server.response.compression_ratio` histogram.
+    /// Both histograms have the attributes of `http.server.response.size`.
+    /// Disabled by default.
+    ///
+    /// [`Compress`]: actix_web::middleware::Compress
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{body::MessageBody as _, dev::Service as _, middleware::Compress, App};
+    /// use actix_web::body::BodySize;
+    /// use actix_web_opentelemetry::{RequestMetrics, UncompressedResponseSize};
+    /// use futures_util::FutureExt as _;
+    ///
+    /// let metrics = RequestMetrics::builder()
+    ///     .with_measured_response_body(true)
+    ///     .with_uncompressed_response_size(true)
+    ///     .build();
+    ///
+    /// let app = App::new()
+    ///     .wrap_fn(|req, srv| {
+    ///         srv.call(req).map(|res| {
+    ///             res.map(|mut res| {
+    ///                 if let BodySize::Sized(size) = res.response().body().size() {
+    ///                     res.response_mut()
+    ///                         .extensions_mut()
+    ///                         .insert(UncompressedResponseSize(size));
+    ///                 }
+    ///                 res
+    ///             })
+    ///         })
+    ///     })
+    ///     .wrap(Compress::default())
+    ///     .wrap(metrics);
+    /// ```
+    pub fn with_uncompressed_response_size(mut self, uncompressed_response_size: bool) -> Self {
+        self.instruments.uncompressed_response_size = uncompressed_response_size;
+        self
+    }
+
+    /// Set the bucket boundaries (in seconds) of the `http.server.duration` histogram
+    ///
+    /// The SDK default boundaries are used if not set, or if a view configured on
+    /// the meter provider overrides them.
+    pub fn with_duration_boundaries(mut self, boundaries: Vec<f64>) -> Self {
+        self.boundaries.duration = Some(boundaries);
+        self
+    }
+
+    /// Set the bucket boundaries (in bytes) of the `http.server.request.size` histogram
+    ///
+    /// Defaults to `[100, 1k, 10k, 100k, 1M, 10M]` bytes.
+    pub fn with_request_size_boundaries(mut self, boundaries: Vec<f64>) -> Self {
+        self.boundaries.request_size = Some(boundaries);
+        self
+    }
+
+    /// Set the bucket boundaries (in bytes) of the `http.server.response.size` histogram
+    ///
+    /// Defaults to `[100, 1k, 10k, 100k, 1M, 10M]` bytes.
+    pub fn with_response_size_boundaries(mut self, boundaries: Vec<f64>) -> Self {
+        self.boundaries.response_size = Some(boundaries);
+        self
+    }
+
+    /// Build the `RequestMetrics` middleware using the given meter provider
+    ///
+    /// Equivalent to [`RequestMetricsBuilder::with_meter_provider`] followed by
+    /// [`RequestMetricsBuilder::build`], for middlewares that must not fall
+    /// back to the global meter provider.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestMetrics;
+    /// use opentelemetry_sdk::metrics::SdkMeterProvider;
+    ///
+    /// let provider = SdkMeterProvider::builder().build();
+    /// let metrics = RequestMetrics::builder().build_with_provider(provider);
+    /// ```
+    pub fn build_with_provider(
+        self,
+        meter_provider: impl MeterProvider + Send + Sync + 'static,
+    ) -> RequestMetrics {
+        self.with_meter_provider(meter_provider).build()
+    }
+
     /// Build the `RequestMetrics` middleware
+    ///
+    /// If neither a meter nor a meter provider is set, the meter is created
+    /// from the global meter provider when this is called. Until
+    /// [`global::set_meter_provider`] has been called the global provider is a
+    /// no-op, so nothing is recorded. Set the global provider before building
+    /// the middleware, or use [`RequestMetricsBuilder::build_with_provider`].
     pub fn build(self) -> RequestMetrics {
-        let meter = self
-            .meter
-            .unwrap_or_else(|| global::meter_provider().meter_with_scope(get_scope()));
+        let meter = self.meter.unwrap_or_else(|| {
+            let scope = self.scope.unwrap_or_else(get_scope);
+            match self.meter_provider {
+                Some(SharedMeterProvider(meter_provider)) => meter_provider.meter_with_scope(scope),
+                None => global::meter_provider().meter_with_scope(scope),
+            }
+        });
 
         RequestMetrics {
-            route_formatter: self.route_formatter,
-            metrics: Arc::new(Metrics::new(meter)),
-            metric_attrs_from_req: self.metric_attrs_from_req.unwrap_or(metrics_attributes_from_request)
+            metrics: Arc::new(Metrics::new(
+                meter,
+                self.boundaries,
+                self.stable_semconv,
+                self.instruments,
+            )),
+            config: Arc::new(MetricsConfig {
+                route_formatter: self.route_formatter,
+                metric_attrs_from_req: self
+                    .metric_attrs_from_req
+                    .unwrap_or(metrics_attributes_from_request),
+                route_allowlist: self.route_allowlist,
+                unmatched_route_label: self
+                    .unmatched_route_label
+                    .unwrap_or(Cow::Borrowed(UNMATCHED_ROUTE)),
+                skip_unmatched_routes: self.skip_unmatched_routes,
+                status_code_class: self.status_code_class,
+                active_requests_attributes: self.active_requests_attributes.or_else(|| {
+                    self.minimal_active_requests_attributes
+                        .then(|| vec![Key::new(HTTP_REQUEST_METHOD), Key::new(HTTP_ROUTE)])
+                }),
+                size_attributes: self.size_attributes,
+                measured_response_body: self.measured_response_body,
+                trace_id_attribute: self.trace_id_attribute,
+                filter: self.filter,
+            }),
         }
     }
 }
 
+/// The instant a request started waiting to be handled, see
+/// [`RequestMetricsBuilder::with_queue_duration`]
+#[derive(Clone, Copy, Debug)]
+pub struct RequestQueueStart(pub Instant);
+
+/// The size (in bytes) of a response body before compression, see
+/// [`RequestMetricsBuilder::with_uncompressed_response_size`]
+#[derive(Clone, Copy, Debug)]
+pub struct UncompressedResponseSize(pub u64);
+
 /// Request metrics tracking
 ///
 /// # Examples
@@ -166,9 +815,24 @@ impl RequestMetricsBuilder {
 /// ```
 #[derive(Clone, Debug)]
 pub struct RequestMetrics {
-    route_formatter: Option<Arc<dyn RouteFormatter + Send + Sync + 'static>>,
     metrics: Arc<Metrics>,
-    metric_attrs_from_req: fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>
+    config: Arc<MetricsConfig>,
+}
+
+/// Options shared between [`RequestMetrics`] and the middleware it builds.
+#[derive(Debug)]
+struct MetricsConfig {
+    route_formatter: Option<Arc<dyn RouteFormatter + Send + Sync + 'static>>,
+    metric_attrs_from_req: MetricAttrsFromReq,
+    route_allowlist: Vec<String>,
+    unmatched_route_label: Cow<'static, str>,
+    skip_unmatched_routes: bool,
+    status_code_class: bool,
+    active_requests_attributes: Option<Vec<Key>>,
+    size_attributes: Option<Vec<Key>>,
+    measured_response_body: bool,
+    trace_id_attribute: bool,
+    filter: Option<RequestFilter>,
 }
 
 impl RequestMetrics {
@@ -178,6 +842,7 @@ impl RequestMetrics {
     }
 }
 
+/// Uses the global meter provider, see [`RequestMetricsBuilder::build`].
 impl Default for RequestMetrics {
     fn default() -> Self {
         RequestMetrics::builder().build()
@@ -194,7 +859,7 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = dev::ServiceResponse<B>;
+    type Response = dev::ServiceResponse<EitherBody<B, MeteredBody<B>>>;
     type Error = actix_web::Error;
     type Transform = RequestMetricsMiddleware<S>;
     type InitError = ();
@@ -204,8 +869,7 @@ where
         let service = RequestMetricsMiddleware {
             service,
             metrics: self.metrics.clone(),
-            route_formatter: self.route_formatter.clone(),
-            metric_attrs_from_req: self.metric_attrs_from_req.clone()
+            config: self.config.clone(),
         };
 
         future::ok(service)
@@ -217,8 +881,7 @@ where
 pub struct RequestMetricsMiddleware<S> {
     service: S,
     metrics: Arc<Metrics>,
-    route_formatter: Option<Arc<dyn RouteFormatter + Send + Sync + 'static>>,
-    metric_attrs_from_req: fn(&dev::ServiceRequest, Cow<'static, str>) -> Vec<KeyValue>
+    config: Arc<MetricsConfig>,
 }
 
 impl<S, B> dev::Service<dev::ServiceRequest> for RequestMetricsMiddleware<S>
@@ -231,7 +894,7 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = dev::ServiceResponse<B>;
+    type Response = dev::ServiceResponse<EitherBody<B, MeteredBody<B>>>;
     type Error = actix_web::Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -240,69 +903,412 @@ where
     fn call(&self, req: dev::ServiceRequest) -> Self::Future {
         let timer = SystemTime::now();
 
-        let mut http_target = req
-            .match_pattern()
+        let match_pattern = req.match_pattern();
+        let filtered = self
+            .config
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(&req));
+        if filtered
+            || self.config.skip_unmatched_routes && match_pattern.is_none()
+            || !self.config.route_allowlist.is_empty()
+                && !match_pattern
+                    .as_ref()
+                    .is_some_and(|pattern| self.config.route_allowlist.contains(pattern))
+        {
+            return Box::pin(
+                self.service
+                    .call(req)
+                    .map(|res| res.map(dev::ServiceResponse::map_into_left_body)),
+            );
+        }
+
+        let mut http_target = match_pattern
             .map(Cow::Owned)
-            .unwrap_or(Cow::Borrowed("default"));
+            .unwrap_or_else(|| self.config.unmatched_route_label.clone());
 
-        if let Some(formatter) = &self.route_formatter {
-            http_target = Cow::Owned(formatter.format(&http_target));
+        if let Some(formatter) = &self.config.route_formatter {
+            http_target = Cow::Owned(formatter.format_request(&req, &http_target));
         }
 
-        let mut attributes = (self.metric_attrs_from_req)(&req, http_target);
-        self.metrics.http_server_active_requests.add(1, &attributes);
+        let mut attributes = (self.config.metric_attrs_from_req)(&req, http_target);
+        let active_attributes = select_attributes(
+            &attributes,
+            self.config.active_requests_attributes.as_deref(),
+        );
+        self.metrics
+            .http_server_active_requests
+            .add(1, active_attributes.as_deref().unwrap_or(&attributes));
 
+        if let Some(histogram) = &self.metrics.http_server_queue_duration {
+            if let Some(RequestQueueStart(start)) = req.extensions().get::<RequestQueueStart>() {
+                histogram.record(start.elapsed().as_secs_f64(), &attributes);
+            }
+        }
+
+        let is_read = is_read_method(req.method());
         let content_length = req
             .headers()
             .get(CONTENT_LENGTH)
             .and_then(|len| len.to_str().ok().and_then(|s| s.parse().ok()))
             .unwrap_or(0);
-        self.metrics
-            .http_server_request_size
-            .record(content_length, &attributes);
+        self.metrics.http_server_request_size.record(
+            content_length,
+            select_attributes(&attributes, self.config.size_attributes.as_deref())
+                .as_deref()
+                .unwrap_or(&attributes),
+        );
 
+        let config = self.config.clone();
         let request_metrics = self.metrics.clone();
         Box::pin(self.service.call(req).map(move |res| {
+            // decrement before any response attribute is added to match the increment
             request_metrics
                 .http_server_active_requests
-                .add(-1, &attributes);
+                .add(-1, active_attributes.as_deref().unwrap_or(&attributes));
 
-            // Ignore actix errors for metrics
-            if let Ok(res) = res {
+            // actix errors are rendered by the app, record the status of their responses
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            if config.status_code_class {
+                attributes.push(KeyValue::new(
+                    HTTP_RESPONSE_STATUS_CODE_CLASS,
+                    format!("{}xx", status.as_u16() / 100),
+                ));
+            } else {
                 attributes.push(KeyValue::new(
                     HTTP_RESPONSE_STATUS_CODE,
-                    res.status().as_u16() as i64,
+                    status.as_u16() as i64,
                 ));
-                let response_size = match res.response().body().size() {
-                    BodySize::Sized(size) => size,
-                    _ => 0,
-                };
-                request_metrics
-                    .http_server_response_size
-                    .record(response_size, &attributes);
+            }
+            // only set on errors to keep the cardinality of successful requests
+            if status.is_server_error() {
+                attributes.push(KeyValue::new(ERROR_TYPE, status.as_str().to_string()));
+            }
+            let duration = timer.elapsed().map(|t| t.as_secs_f64()).unwrap_or_default();
+            let trace_id = config
+                .trace_id_attribute
+                .then(|| OtelContext::current().span().span_context().clone())
+                .filter(|span_context| span_context.is_sampled())
+                .map(|span_context| KeyValue::new(TRACE_ID, span_context.trace_id().to_string()));
+            match trace_id {
+                Some(trace_id) => {
+                    let mut attributes = attributes.clone();
+                    attributes.push(trace_id);
+                    request_metrics
+                        .http_server_duration
+                        .record(duration, &attributes);
+                }
+                None => request_metrics
+                    .http_server_duration
+                    .record(duration, &attributes),
+            }
+            if let Some((read, write)) = &request_metrics.http_server_duration_by_method {
+                let histogram = if is_read { read } else { write };
+                histogram.record(duration, &attributes);
+            }
+            if let Some(request_count) = &request_metrics.http_server_request_count {
+                request_count.add(1, &attributes);
+            }
 
-                request_metrics.http_server_duration.record(
-                    timer.elapsed().map(|t| t.as_secs_f64()).unwrap_or_default(),
-                    &attributes,
-                );
+            let res = res?;
+            let first_byte_recorder =
+                request_metrics
+                    .http_server_time_to_first_byte
+                    .clone()
+                    .map(|histogram| FirstByteRecorder {
+                        histogram,
+                        attributes: attributes.clone(),
+                        start: timer,
+                    });
+            // the size of the body as returned by the wrapped service, which is
+            // compressed if it wraps the `Compress` middleware
+            let response_size = match res.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                BodySize::Stream if config.measured_response_body => None,
+                _ => Some(0),
+            };
+            let size_attributes = select_attributes(&attributes, config.size_attributes.as_deref())
+                .unwrap_or(attributes);
+            let compression_ratio = request_metrics
+                .http_server_uncompressed_response_size
+                .as_ref()
+                .and_then(|(histogram, ratio)| {
+                    let UncompressedResponseSize(size) = *res
+                        .response()
+                        .extensions()
+                        .get::<UncompressedResponseSize>()?;
+                    histogram.record(size, &size_attributes);
+                    Some(CompressionRatioRecorder {
+                        histogram: ratio.clone(),
+                        uncompressed_size: size,
+                    })
+                });
+            let recorder = match response_size {
+                Some(size) => {
+                    request_metrics
+                        .http_server_response_size
+                        .record(size, &size_attributes);
+                    if let Some(compression_ratio) = compression_ratio {
+                        compression_ratio.record(size, &size_attributes);
+                    }
+                    None
+                }
+                None => Some(ResponseSizeRecorder {
+                    histogram: request_metrics.http_server_response_size.clone(),
+                    attributes: size_attributes,
+                    size: 0,
+                    compression_ratio,
+                }),
+            };
 
-                Ok(res)
-            } else {
-                res
+            // only wrap bodies that are metered to keep the fast path of sized bodies
+            if recorder.is_none() && first_byte_recorder.is_none() {
+                return Ok(res.map_into_left_body());
             }
+            Ok(res.map_body(|_, body| {
+                EitherBody::right(MeteredBody::new(body, recorder, first_byte_recorder))
+            }))
         }))
     }
 }
 
+/// Whether requests with the given method only read data.
+fn is_read_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Select the attributes with the given keys, if any.
+fn select_attributes(attributes: &[KeyValue], keys: Option<&[Key]>) -> Option<Vec<KeyValue>> {
+    keys.map(|keys| {
+        attributes
+            .iter()
+            .filter(|kv| keys.contains(&kv.key))
+            .cloned()
+            .collect()
+    })
+}
+
+pin_project! {
+    /// Response body of the [`RequestMetricsMiddleware`] when it meters the body
+    ///
+    /// Counts the bytes of streamed bodies when enabled by
+    /// [`RequestMetricsBuilder::with_measured_response_body`], and records the
+    /// time to the first chunk when enabled by
+    /// [`RequestMetricsBuilder::with_time_to_first_byte`].
+    pub struct MeteredBody<B> {
+        #[pin]
+        body: B,
+        recorder: Option<ResponseSizeRecorder>,
+        first_byte_recorder: Option<FirstByteRecorder>,
+    }
+}
+
+impl<B> MeteredBody<B> {
+    fn new(
+        body: B,
+        recorder: Option<ResponseSizeRecorder>,
+        first_byte_recorder: Option<FirstByteRecorder>,
+    ) -> Self {
+        MeteredBody {
+            body,
+            recorder,
+            first_byte_recorder,
+        }
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for MeteredBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeteredBody")
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl<B: MessageBody> MessageBody for MeteredBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let item = ready!(this.body.poll_next(cx));
+        if let Some(first_byte_recorder) = this.first_byte_recorder.take() {
+            first_byte_recorder.record();
+        }
+        if let (Some(recorder), Some(Ok(chunk))) = (this.recorder.as_mut(), &item) {
+            recorder.size += chunk.len() as u64;
+        }
+        Poll::Ready(item)
+    }
+
+    fn try_into_bytes(self) -> Result<Bytes, Self> {
+        let MeteredBody {
+            body,
+            recorder,
+            first_byte_recorder,
+        } = self;
+        match body.try_into_bytes() {
+            Ok(bytes) => {
+                if let Some(first_byte_recorder) = first_byte_recorder {
+                    first_byte_recorder.record();
+                }
+                if let Some(mut recorder) = recorder {
+                    recorder.size += bytes.len() as u64;
+                }
+                Ok(bytes)
+            }
+            Err(body) => Err(MeteredBody::new(body, recorder, first_byte_recorder)),
+        }
+    }
+}
+
+/// Records the size of a streamed response body once the body is dropped.
+struct ResponseSizeRecorder {
+    histogram: Histogram<u64>,
+    attributes: Vec<KeyValue>,
+    size: u64,
+    compression_ratio: Option<CompressionRatioRecorder>,
+}
+
+impl Drop for ResponseSizeRecorder {
+    fn drop(&mut self) {
+        self.histogram.record(self.size, &self.attributes);
+        if let Some(compression_ratio) = self.compression_ratio.take() {
+            compression_ratio.record(self.size, &self.attributes);
+        }
+    }
+}
+
+/// Records the ratio of the uncompressed to the compressed response size.
+struct CompressionRatioRecorder {
+    histogram: Histogram<f64>,
+    uncompressed_size: u64,
+}
+
+impl CompressionRatioRecorder {
+    fn record(self, compressed_size: u64, attributes: &[KeyValue]) {
+        if compressed_size > 0 {
+            self.histogram.record(
+                self.uncompressed_size as f64 / compressed_size as f64,
+                attributes,
+            );
+        }
+    }
+}
+
+/// Records the time until the first poll of a response body yields.
+struct FirstByteRecorder {
+    histogram: Histogram<f64>,
+    attributes: Vec<KeyValue>,
+    start: SystemTime,
+}
+
+impl FirstByteRecorder {
+    fn record(self) {
+        let elapsed = self
+            .start
+            .elapsed()
+            .map(|t| t.as_secs_f64())
+            .unwrap_or_default();
+        self.histogram.record(elapsed, &self.attributes);
+    }
+}
+
 #[cfg(feature = "metrics-prometheus")]
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics-prometheus")))]
 pub(crate) mod prometheus {
-    use actix_web::{dev, http::StatusCode};
+    use actix_web::{
+        dev,
+        http::{
+            header::{HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+            StatusCode,
+        },
+    };
+    use flate2::{write::GzEncoder, Compression};
     use futures_util::future::{self, LocalBoxFuture};
     use opentelemetry_sdk::metrics::MetricError;
-    use prometheus::{Encoder, Registry, TextEncoder};
+    use prometheus::{
+        proto::{Metric, MetricFamily, MetricType},
+        Encoder, Registry, TextEncoder,
+    };
+    use std::{fmt::Write as _, io::Write};
+
+    // `TextEncoder::format_type` with the charset of the encoded output
+    const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+    const OPENMETRICS_CONTENT_TYPE: &str =
+        "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
     /// Prometheus request metrics service
+    ///
+    /// Metrics are served in the [OpenMetrics] text format to scrapers that
+    /// prefer `application/openmetrics-text` in their `Accept` header, and in
+    /// the classic Prometheus text exposition format otherwise. Responses are
+    /// gzip compressed when the request accepts the `gzip` encoding.
+    ///
+    /// [OpenMetrics]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{http::header, test, web, App};
+    /// use actix_web_opentelemetry::PrometheusMetricsHandler;
+    /// use prometheus::{IntCounter, Registry};
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() {
+    /// let registry = Registry::new();
+    /// let requests = IntCounter::new("requests_total", "Requests served").unwrap();
+    /// registry.register(Box::new(requests.clone())).unwrap();
+    /// requests.inc();
+    ///
+    /// let app = test::init_service(
+    ///     App::new().route("/metrics", web::get().to(PrometheusMetricsHandler::new(registry))),
+    /// )
+    /// .await;
+    /// let req = test::TestRequest::get()
+    ///     .uri("/metrics")
+    ///     .insert_header((header::ACCEPT, "application/openmetrics-text; version=1.0.0"))
+    ///     .to_request();
+    /// let res = test::call_service(&app, req).await;
+    /// assert_eq!(
+    ///     res.headers().get(header::CONTENT_TYPE).unwrap(),
+    ///     "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    /// );
+    /// let body = test::read_body(res).await;
+    /// assert_eq!(
+    ///     body,
+    ///     concat!(
+    ///         "# HELP requests Requests served\n",
+    ///         "# TYPE requests counter\n",
+    ///         "requests_total 1\n",
+    ///         "# EOF\n",
+    ///     )
+    /// );
+    ///
+    /// // any other `Accept` value is served the classic text format
+    /// let req = test::TestRequest::get()
+    ///     .uri("/metrics")
+    ///     .insert_header((header::ACCEPT, "application/json"))
+    ///     .to_request();
+    /// let res = test::call_service(&app, req).await;
+    /// assert_eq!(
+    ///     res.headers().get(header::CONTENT_TYPE).unwrap(),
+    ///     "text/plain; version=0.0.4; charset=utf-8"
+    /// );
+    /// # }
+    /// ```
     #[derive(Clone, Debug)]
     pub struct PrometheusMetricsHandler {
         prometheus_registry: Registry,
@@ -318,33 +1324,427 @@ pub(crate) mod prometheus {
     }
 
     impl PrometheusMetricsHandler {
-        fn metrics(&self) -> String {
-            let encoder = TextEncoder::new();
+        fn metrics(&self, openmetrics: bool) -> Result<String, MetricError> {
             let metric_families = self.prometheus_registry.gather();
-            let mut buf = Vec::new();
-            if let Err(err) = encoder.encode(&metric_families[..], &mut buf) {
-                tracing::error!(
-                    name: "encode_failure",
-                    target: env!("CARGO_PKG_NAME"),
-                    name = "encode_failure",
-                    error = MetricError::Other(err.to_string()).to_string(),
-                    ""
-                );
+            if openmetrics {
+                return Ok(encode_openmetrics(&metric_families));
             }
 
-            String::from_utf8(buf).unwrap_or_default()
+            let encoder = TextEncoder::new();
+            let mut buf = Vec::new();
+            encoder
+                .encode(&metric_families[..], &mut buf)
+                .map_err(|err| MetricError::Other(err.to_string()))?;
+
+            String::from_utf8(buf).map_err(|err| MetricError::Other(err.to_string()))
         }
     }
 
     impl dev::Handler<actix_web::HttpRequest> for PrometheusMetricsHandler {
-        type Output = Result<actix_web::HttpResponse<String>, actix_web::error::Error>;
+        type Output = Result<actix_web::HttpResponse<Vec<u8>>, actix_web::error::Error>;
         type Future = LocalBoxFuture<'static, Self::Output>;
 
-        fn call(&self, _req: actix_web::HttpRequest) -> Self::Future {
-            Box::pin(future::ok(actix_web::HttpResponse::with_body(
+        fn call(&self, req: actix_web::HttpRequest) -> Self::Future {
+            let openmetrics = prefers_openmetrics(&req);
+            let metrics = match self.metrics(openmetrics) {
+                Ok(metrics) => metrics,
+                Err(err) => {
+                    tracing::error!(
+                        name: "encode_failure",
+                        target: env!("CARGO_PKG_NAME"),
+                        name = "encode_failure",
+                        error = err.to_string(),
+                        ""
+                    );
+                    return Box::pin(future::ok(actix_web::HttpResponse::with_body(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        b"Failed to encode metrics".to_vec(),
+                    )));
+                }
+            };
+
+            let compressed = if accepts_gzip(&req) {
+                gzip_encode(metrics.as_bytes())
+            } else {
+                None
+            };
+            let is_compressed = compressed.is_some();
+
+            let mut response = actix_web::HttpResponse::with_body(
                 StatusCode::OK,
-                self.metrics(),
-            )))
+                compressed.unwrap_or_else(|| metrics.into_bytes()),
+            );
+            let headers = response.headers_mut();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(if openmetrics {
+                    OPENMETRICS_CONTENT_TYPE
+                } else {
+                    TEXT_CONTENT_TYPE
+                }),
+            );
+            headers.insert(VARY, HeaderValue::from_static("accept, accept-encoding"));
+            if is_compressed {
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            }
+            Box::pin(future::ok(response))
+        }
+    }
+
+    fn accepts_gzip(req: &actix_web::HttpRequest) -> bool {
+        req.headers()
+            .get_all(ACCEPT_ENCODING)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| {
+                let mut params = coding.split(';');
+                let accepted = params
+                    .next()
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case("gzip"));
+                // `gzip;q=0` explicitly refuses the encoding
+                accepted
+                    && !params
+                        .any(|param| matches!(param.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+            })
+    }
+
+    fn gzip_encode(body: &[u8]) -> Option<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).ok()?;
+        encoder.finish().ok()
+    }
+
+    // Serve OpenMetrics when it is accepted with at least the quality of the
+    // classic text format, which remains the fallback for any other `Accept`
+    fn prefers_openmetrics(req: &actix_web::HttpRequest) -> bool {
+        let mut openmetrics = 0.0;
+        let mut text = 0.0;
+        for media_range in req
+            .headers()
+            .get_all(ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut params = media_range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if media_type.eq_ignore_ascii_case("application/openmetrics-text") {
+                openmetrics = f32::max(openmetrics, quality);
+            } else if media_type.eq_ignore_ascii_case("text/plain")
+                || media_type.eq_ignore_ascii_case("text/*")
+                || media_type == "*/*"
+            {
+                text = f32::max(text, quality);
+            }
+        }
+
+        openmetrics > 0.0 && openmetrics >= text
+    }
+
+    // https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#text-format
+    fn encode_openmetrics(metric_families: &[MetricFamily]) -> String {
+        let mut buf = String::new();
+        for family in metric_families {
+            let name = family.get_name();
+            let (name, metric_type) = match family.get_field_type() {
+                MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+                MetricType::GAUGE => (name, "gauge"),
+                MetricType::HISTOGRAM => (name, "histogram"),
+                MetricType::SUMMARY => (name, "summary"),
+                MetricType::UNTYPED => (name, "unknown"),
+            };
+            if !family.get_help().is_empty() {
+                let _ = writeln!(buf, "# HELP {} {}", name, escape(family.get_help()));
+            }
+            let _ = writeln!(buf, "# TYPE {} {}", name, metric_type);
+
+            for metric in family.get_metric() {
+                match family.get_field_type() {
+                    MetricType::COUNTER => write_sample(
+                        &mut buf,
+                        name,
+                        "_total",
+                        metric,
+                        None,
+                        metric.get_counter().get_value(),
+                    ),
+                    MetricType::GAUGE => write_sample(
+                        &mut buf,
+                        name,
+                        "",
+                        metric,
+                        None,
+                        metric.get_gauge().get_value(),
+                    ),
+                    MetricType::UNTYPED => write_sample(
+                        &mut buf,
+                        name,
+                        "",
+                        metric,
+                        None,
+                        metric.get_untyped().get_value(),
+                    ),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let mut has_inf = false;
+                        for bucket in histogram.get_bucket() {
+                            let upper_bound = bucket.get_upper_bound();
+                            has_inf |= upper_bound == f64::INFINITY;
+                            write_sample(
+                                &mut buf,
+                                name,
+                                "_bucket",
+                                metric,
+                                Some(("le", &format_float(upper_bound))),
+                                bucket.get_cumulative_count() as f64,
+                            );
+                        }
+                        if !has_inf {
+                            write_sample(
+                                &mut buf,
+                                name,
+                                "_bucket",
+                                metric,
+                                Some(("le", "+Inf")),
+                                histogram.get_sample_count() as f64,
+                            );
+                        }
+                        write_sample(
+                            &mut buf,
+                            name,
+                            "_sum",
+                            metric,
+                            None,
+                            histogram.get_sample_sum(),
+                        );
+                        write_sample(
+                            &mut buf,
+                            name,
+                            "_count",
+                            metric,
+                            None,
+                            histogram.get_sample_count() as f64,
+                        );
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        for quantile in summary.get_quantile() {
+                            write_sample(
+                                &mut buf,
+                                name,
+                                "",
+                                metric,
+                                Some(("quantile", &format_float(quantile.get_quantile()))),
+                                quantile.get_value(),
+                            );
+                        }
+                        write_sample(
+                            &mut buf,
+                            name,
+                            "_sum",
+                            metric,
+                            None,
+                            summary.get_sample_sum(),
+                        );
+                        write_sample(
+                            &mut buf,
+                            name,
+                            "_count",
+                            metric,
+                            None,
+                            summary.get_sample_count() as f64,
+                        );
+                    }
+                }
+            }
+        }
+        buf.push_str("# EOF\n");
+        buf
+    }
+
+    fn write_sample(
+        buf: &mut String,
+        name: &str,
+        suffix: &str,
+        metric: &Metric,
+        additional_label: Option<(&str, &str)>,
+        value: f64,
+    ) {
+        buf.push_str(name);
+        buf.push_str(suffix);
+        let labels = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name(), label.get_value()))
+            .chain(additional_label);
+        for (i, (label, value)) in labels.enumerate() {
+            buf.push(if i == 0 { '{' } else { ',' });
+            let _ = write!(buf, "{}=\"{}\"", label, escape(value));
+        }
+        if !metric.get_label().is_empty() || additional_label.is_some() {
+            buf.push('}');
+        }
+        let _ = write!(buf, " {}", format_float(value));
+        // OpenMetrics timestamps are in seconds
+        if metric.get_timestamp_ms() != 0 {
+            let _ = write!(buf, " {}", metric.get_timestamp_ms() as f64 / 1000.0);
+        }
+        buf.push('\n');
+    }
+
+    fn format_float(value: f64) -> String {
+        if value == f64::INFINITY {
+            "+Inf".to_string()
+        } else if value == f64::NEG_INFINITY {
+            "-Inf".to_string()
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('"', "\\\"")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{RequestMetrics, RequestMetricsBuilder};
+        use actix_web::{test as actix_test, web, App};
+        use opentelemetry_sdk::metrics::SdkMeterProvider;
+        use prometheus::{
+            proto::{Gauge, LabelPair, Quantile, Summary},
+            HistogramOpts, HistogramVec, IntCounterVec, Opts,
+        };
+
+        #[test]
+        fn openmetrics_histograms_and_labels() {
+            let registry = Registry::new();
+            let histogram = HistogramVec::new(
+                HistogramOpts::new("request_duration_seconds", "Request \"duration\"\n")
+                    .buckets(vec![0.1, 1.0]),
+                &["route"],
+            )
+            .unwrap();
+            registry.register(Box::new(histogram.clone())).unwrap();
+            let requests =
+                IntCounterVec::new(Opts::new("requests_total", "Requests"), &["method"]).unwrap();
+            registry.register(Box::new(requests.clone())).unwrap();
+
+            let route = histogram.with_label_values(&["/a\"b\\c\nd"]);
+            route.observe(0.5);
+            route.observe(2.0);
+            requests.with_label_values(&["GET"]).inc_by(3);
+
+            assert_eq!(
+                encode_openmetrics(&registry.gather()),
+                concat!(
+                    "# HELP request_duration_seconds Request \\\"duration\\\"\\n\n",
+                    "# TYPE request_duration_seconds histogram\n",
+                    "request_duration_seconds_bucket{route=\"/a\\\"b\\\\c\\nd\",le=\"0.1\"} 0\n",
+                    "request_duration_seconds_bucket{route=\"/a\\\"b\\\\c\\nd\",le=\"1\"} 1\n",
+                    "request_duration_seconds_bucket{route=\"/a\\\"b\\\\c\\nd\",le=\"+Inf\"} 2\n",
+                    "request_duration_seconds_sum{route=\"/a\\\"b\\\\c\\nd\"} 2.5\n",
+                    "request_duration_seconds_count{route=\"/a\\\"b\\\\c\\nd\"} 2\n",
+                    "# HELP requests Requests\n",
+                    "# TYPE requests counter\n",
+                    "requests_total{method=\"GET\"} 3\n",
+                    "# EOF\n",
+                )
+            );
+        }
+
+        #[test]
+        fn openmetrics_summaries_and_timestamps() {
+            let mut quantile = Quantile::default();
+            quantile.set_quantile(0.5);
+            quantile.set_value(0.25);
+            let mut summary = Summary::default();
+            summary.set_quantile(vec![quantile].into());
+            summary.set_sample_sum(1.5);
+            summary.set_sample_count(4);
+            let mut metric = Metric::default();
+            metric.set_summary(summary);
+            let mut latency = MetricFamily::default();
+            latency.set_name("latency_seconds".to_string());
+            latency.set_field_type(MetricType::SUMMARY);
+            latency.set_metric(vec![metric].into());
+
+            let mut label = LabelPair::default();
+            label.set_name("pool".to_string());
+            label.set_value("main".to_string());
+            let mut gauge = Gauge::default();
+            gauge.set_value(f64::NEG_INFINITY);
+            let mut metric = Metric::default();
+            metric.set_label(vec![label].into());
+            metric.set_gauge(gauge);
+            metric.set_timestamp_ms(1_500);
+            let mut connections = MetricFamily::default();
+            connections.set_name("connections".to_string());
+            connections.set_field_type(MetricType::GAUGE);
+            connections.set_metric(vec![metric].into());
+
+            assert_eq!(
+                encode_openmetrics(&[latency, connections]),
+                concat!(
+                    "# TYPE latency_seconds summary\n",
+                    "latency_seconds{quantile=\"0.5\"} 0.25\n",
+                    "latency_seconds_sum 1.5\n",
+                    "latency_seconds_count 4\n",
+                    "# TYPE connections gauge\n",
+                    "connections{pool=\"main\"} -Inf 1.5\n",
+                    "# EOF\n",
+                )
+            );
+        }
+
+        #[actix_web::test]
+        async fn openmetrics_request_metrics() {
+            let registry = Registry::new();
+            let exporter = opentelemetry_prometheus::exporter()
+                .with_registry(registry.clone())
+                .without_target_info()
+                .without_scope_info()
+                .build()
+                .unwrap();
+            let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+            let metrics: RequestMetrics = RequestMetricsBuilder::new()
+                .with_meter_provider(provider.clone())
+                .build();
+            let app = actix_test::init_service(
+                App::new()
+                    .wrap(metrics)
+                    .route("/users/{id}", web::get().to(|| async { "ok" })),
+            )
+            .await;
+            let req = actix_test::TestRequest::get().uri("/users/42").to_request();
+            actix_test::call_service(&app, req).await;
+
+            // without counters, both formats only differ in the terminator
+            let metric_families = registry.gather();
+            let mut text = Vec::new();
+            TextEncoder::new()
+                .encode(&metric_families, &mut text)
+                .unwrap();
+            let openmetrics = encode_openmetrics(&metric_families);
+            assert_eq!(
+                openmetrics,
+                format!("{}# EOF\n", String::from_utf8(text).unwrap())
+            );
+            assert!(openmetrics.lines().any(|line| {
+                line.starts_with("http_server_duration_seconds_bucket{")
+                    && line.contains("http_route=\"/users/{id}\"")
+                    && line.ends_with("le=\"+Inf\"} 1")
+            }));
+            assert!(openmetrics.lines().any(|line| {
+                line.starts_with("http_server_response_size_bytes_sum{") && line.ends_with("} 2")
+            }));
         }
     }
 }
@@ -1,11 +1,15 @@
 use opentelemetry::InstrumentationScope;
 
+pub(crate) mod filter;
 #[cfg(feature = "metrics")]
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
 pub(crate) mod metrics;
 pub(crate) mod route_formatter;
 pub(crate) mod trace;
 
+/// The route recorded for requests that matched no route, unless configured.
+pub(crate) const UNMATCHED_ROUTE: &str = "default";
+
 pub(crate) fn get_scope() -> InstrumentationScope {
     InstrumentationScope::builder("actix-web-opentelemetry")
         .with_version(env!("CARGO_PKG_VERSION"))
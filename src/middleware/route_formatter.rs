@@ -2,6 +2,8 @@
 //!
 //! Format routes from paths.
 
+use actix_web::dev::ServiceRequest;
+
 /// Interface for formatting routes from paths.
 ///
 /// This crate will render the actix web [match pattern] by default. E.g. for
@@ -27,8 +29,383 @@
 ///
 /// // now a match with pattern `/USERS/{id}` would be recorded as `/users/{id}`
 /// ```
+///
+/// Formatters can also depend on other parts of the request by overriding
+/// [`RouteFormatter::format_request`]:
+///
+/// ```
+/// use actix_web::{dev::ServiceRequest, http::Method};
+/// use actix_web_opentelemetry::RouteFormatter;
+///
+/// // A formatter grouping all writes to a path prefix under one route.
+/// #[derive(Debug)]
+/// struct WritesFormatter;
+///
+/// impl RouteFormatter for WritesFormatter {
+///     fn format(&self, path: &str) -> String {
+///         path.to_string()
+///     }
+///
+///     fn format_request(&self, req: &ServiceRequest, path: &str) -> String {
+///         match req.method() {
+///             &Method::GET | &Method::HEAD => self.format(path),
+///             _ => "/writes".to_string(),
+///         }
+///     }
+/// }
+/// ```
 pub trait RouteFormatter: std::fmt::Debug {
     /// Function from path to route.
     /// e.g. /users/123 -> /users/:id
     fn format(&self, path: &str) -> String;
+
+    /// Function from a server request and its path to route.
+    ///
+    /// Used by the server middlewares, defaults to [`RouteFormatter::format`].
+    /// Client requests are always formatted with [`RouteFormatter::format`].
+    fn format_request(&self, req: &ServiceRequest, path: &str) -> String {
+        let _ = req;
+        self.format(path)
+    }
+}
+
+/// Formatter that replaces numeric path segments with a placeholder.
+///
+/// Only whole segments are replaced, so `/v2/orders/98213` is formatted as
+/// `/v2/orders/*`.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{NumericIdFormatter, RouteFormatter};
+///
+/// let formatter = NumericIdFormatter::new();
+/// assert_eq!(formatter.format("/orders/98213/items/55"), "/orders/*/items/*");
+///
+/// // keep short numeric segments such as years or versions
+/// let formatter = NumericIdFormatter::new()
+///     .with_placeholder(":id")
+///     .with_min_len(5);
+/// assert_eq!(formatter.format("/2024/orders/98213"), "/2024/orders/:id");
+/// ```
+#[derive(Clone, Debug)]
+pub struct NumericIdFormatter {
+    placeholder: String,
+    min_len: usize,
+}
+
+impl NumericIdFormatter {
+    /// Create a formatter replacing numeric segments with `*`.
+    pub fn new() -> Self {
+        NumericIdFormatter::default()
+    }
+
+    /// Set the placeholder numeric segments are replaced with.
+    pub fn with_placeholder(mut self, placeholder: impl Into<String>) -> Self {
+        self.placeholder = placeholder.into();
+        self
+    }
+
+    /// Only replace numeric segments with at least `min_len` digits.
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+}
+
+impl Default for NumericIdFormatter {
+    fn default() -> Self {
+        NumericIdFormatter {
+            placeholder: "*".to_string(),
+            min_len: 1,
+        }
+    }
+}
+
+impl RouteFormatter for NumericIdFormatter {
+    fn format(&self, path: &str) -> String {
+        replace_segments(path, &self.placeholder, |segment| {
+            segment.len() >= self.min_len && segment.bytes().all(|b| b.is_ascii_digit())
+        })
+    }
+}
+
+/// Formatter that applies multiple formatters in order.
+///
+/// Each formatter receives the output of the previous one.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{ChainedRouteFormatter, NumericIdFormatter, RouteFormatter};
+///
+/// #[derive(Debug)]
+/// struct LowercaseFormatter;
+///
+/// impl RouteFormatter for LowercaseFormatter {
+///     fn format(&self, path: &str) -> String {
+///         path.to_lowercase()
+///     }
+/// }
+///
+/// let formatter = ChainedRouteFormatter::default()
+///     .then(NumericIdFormatter::new())
+///     .then(LowercaseFormatter);
+/// assert_eq!(formatter.format("/Orders/98213"), "/orders/*");
+/// ```
+#[derive(Debug, Default)]
+pub struct ChainedRouteFormatter {
+    formatters: Vec<Box<dyn RouteFormatter + Send + Sync>>,
+}
+
+impl ChainedRouteFormatter {
+    /// Create a formatter applying the given formatters from first to last.
+    pub fn new(formatters: Vec<Box<dyn RouteFormatter + Send + Sync>>) -> Self {
+        ChainedRouteFormatter { formatters }
+    }
+
+    /// Apply `formatter` after the existing formatters.
+    pub fn then<T: RouteFormatter + Send + Sync + 'static>(mut self, formatter: T) -> Self {
+        self.formatters.push(Box::new(formatter));
+        self
+    }
+}
+
+impl RouteFormatter for ChainedRouteFormatter {
+    fn format(&self, path: &str) -> String {
+        self.formatters
+            .iter()
+            .fold(path.to_string(), |path, formatter| formatter.format(&path))
+    }
+
+    fn format_request(&self, req: &ServiceRequest, path: &str) -> String {
+        self.formatters
+            .iter()
+            .fold(path.to_string(), |path, formatter| {
+                formatter.format_request(req, &path)
+            })
+    }
+}
+
+/// Formatter that replaces all matches of a regular expression.
+///
+/// The replacement follows [`regex::Regex::replace_all`] syntax, so `$name`
+/// refers to capture groups.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{RegexRouteFormatter, RouteFormatter};
+///
+/// let formatter = RegexRouteFormatter::new(r"/\d+", "/:id").unwrap();
+/// assert_eq!(formatter.format("/users/123/orders/456"), "/users/:id/orders/:id");
+/// ```
+#[cfg(feature = "regex")]
+#[cfg_attr(docsrs, doc(cfg(feature = "regex")))]
+#[derive(Clone, Debug)]
+pub struct RegexRouteFormatter {
+    regex: regex::Regex,
+    replacement: String,
+}
+
+#[cfg(feature = "regex")]
+impl RegexRouteFormatter {
+    /// Compile `pattern` into a formatter replacing its matches with `replacement`.
+    pub fn new(pattern: &str, replacement: impl Into<String>) -> Result<Self, regex::Error> {
+        Ok(RegexRouteFormatter {
+            regex: regex::Regex::new(pattern)?,
+            replacement: replacement.into(),
+        })
+    }
+}
+
+#[cfg(feature = "regex")]
+impl RouteFormatter for RegexRouteFormatter {
+    fn format(&self, path: &str) -> String {
+        self.regex
+            .replace_all(path, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Formatter that replaces [ULID] path segments with `*`.
+///
+/// ULIDs are matched case-insensitively as 26 Crockford base32 characters.
+///
+/// [ULID]: https://github.com/ulid/spec
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{RouteFormatter, UlidWildcardFormatter};
+///
+/// let formatter = UlidWildcardFormatter;
+/// assert_eq!(formatter.format("/users/01ARZ3NDEKTSV4RRFFQ69G5FAV"), "/users/*");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct UlidWildcardFormatter;
+
+fn is_ulid(segment: &str) -> bool {
+    // the first character only encodes 3 bits, larger values overflow 128 bits
+    segment.len() == 26
+        && segment.starts_with(|c: char| ('0'..='7').contains(&c))
+        && segment.bytes().all(|b| {
+            b.is_ascii_digit()
+                || (b.is_ascii_alphabetic()
+                    && !matches!(b.to_ascii_uppercase(), b'I' | b'L' | b'O' | b'U'))
+        })
+}
+
+impl RouteFormatter for UlidWildcardFormatter {
+    fn format(&self, path: &str) -> String {
+        replace_segments(path, "*", is_ulid)
+    }
+}
+
+/// Formatter that replaces UUID path segments with `*`.
+///
+/// Segments in the hyphenated form, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`,
+/// are replaced regardless of case and version.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{RouteFormatter, UuidWildcardFormatter};
+///
+/// let formatter = UuidWildcardFormatter;
+/// assert_eq!(
+///     formatter.format("/users/67e55044-10b1-426f-9247-bb680e5fe0c8/profile"),
+///     "/users/*/profile"
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct UuidWildcardFormatter;
+
+fn is_uuid(segment: &str) -> bool {
+    segment.len() == 36
+        && segment.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+impl RouteFormatter for UuidWildcardFormatter {
+    fn format(&self, path: &str) -> String {
+        replace_segments(path, "*", is_uuid)
+    }
+}
+
+/// The route formatters provided by this crate, for configuring common id
+/// masking in one line.
+///
+/// Use [`ChainedRouteFormatter`] to combine them with custom formatters.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{BuiltinRouteFormatter, RequestTracing, RouteFormatter};
+///
+/// let tracing =
+///     RequestTracing::new().with_builtin_formatter(BuiltinRouteFormatter::UuidAndNumericId);
+///
+/// let formatter = BuiltinRouteFormatter::UuidAndNumericId;
+/// assert_eq!(
+///     formatter.format("/orders/98213/items/67e55044-10b1-426f-9247-bb680e5fe0c8"),
+///     "/orders/*/items/*"
+/// );
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub enum BuiltinRouteFormatter {
+    /// Replace numeric segments, see [`NumericIdFormatter`].
+    NumericId,
+    /// Replace UUID segments, see [`UuidWildcardFormatter`].
+    Uuid,
+    /// Replace ULID segments, see [`UlidWildcardFormatter`].
+    Ulid,
+    /// Replace UUID and numeric segments.
+    UuidAndNumericId,
+    /// Replace UUID, ULID and numeric segments.
+    All,
+}
+
+impl BuiltinRouteFormatter {
+    /// The crate formatters this variant stands for, applied in order.
+    pub(crate) fn formatter(self) -> ChainedRouteFormatter {
+        let formatter = ChainedRouteFormatter::default();
+        match self {
+            BuiltinRouteFormatter::NumericId => formatter.then(NumericIdFormatter::default()),
+            BuiltinRouteFormatter::Uuid => formatter.then(UuidWildcardFormatter),
+            BuiltinRouteFormatter::Ulid => formatter.then(UlidWildcardFormatter),
+            BuiltinRouteFormatter::UuidAndNumericId => formatter
+                .then(UuidWildcardFormatter)
+                .then(NumericIdFormatter::default()),
+            BuiltinRouteFormatter::All => formatter
+                .then(UuidWildcardFormatter)
+                .then(UlidWildcardFormatter)
+                .then(NumericIdFormatter::default()),
+        }
+    }
+}
+
+impl RouteFormatter for BuiltinRouteFormatter {
+    fn format(&self, path: &str) -> String {
+        self.formatter().format(path)
+    }
+}
+
+/// Formatter that replaces token-like path segments with `*`.
+///
+/// A segment is replaced if it has at least `min_len` characters, all of which
+/// are in `charset`. Choose `min_len` above the length of your longest static
+/// path segment made of the same characters.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web_opentelemetry::{RouteFormatter, TokenWildcardFormatter};
+///
+/// // nanoid style tokens
+/// let formatter = TokenWildcardFormatter::new(
+///     12,
+///     "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_-",
+/// );
+/// assert_eq!(formatter.format("/files/V1StGXR8_Z5j/raw"), "/files/*/raw");
+/// ```
+#[derive(Clone, Debug)]
+pub struct TokenWildcardFormatter {
+    min_len: usize,
+    charset: String,
+}
+
+impl TokenWildcardFormatter {
+    /// Create a formatter replacing segments of at least `min_len` characters
+    /// from `charset` with `*`.
+    pub fn new(min_len: usize, charset: impl Into<String>) -> Self {
+        TokenWildcardFormatter {
+            min_len,
+            charset: charset.into(),
+        }
+    }
+}
+
+impl RouteFormatter for TokenWildcardFormatter {
+    fn format(&self, path: &str) -> String {
+        replace_segments(path, "*", |segment| {
+            segment.chars().count() >= self.min_len.max(1)
+                && segment.chars().all(|c| self.charset.contains(c))
+        })
+    }
+}
+
+fn replace_segments(path: &str, placeholder: &str, matches: impl Fn(&str) -> bool) -> String {
+    path.split('/')
+        .map(|segment| {
+            if !segment.is_empty() && matches(segment) {
+                placeholder
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
 }
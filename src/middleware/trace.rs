@@ -1,110 +1,1407 @@
-use std::{borrow::Cow, rc::Rc, task::Poll};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::Cell,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    rc::Rc,
+    task::{ready, Poll},
+    time::{Duration, Instant},
+};
+
+use actix_http::{BoxedPayloadStream, HttpMessage as _};
+use actix_web::{
+    dev::{
+        Extensions, Path, Payload, ResourceDef, Service, ServiceRequest, ServiceResponse,
+        Transform, Url,
+    },
+    error::PayloadError,
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, UPGRADE},
+        StatusCode,
+    },
+    web::Bytes,
+    Error,
+};
+use futures_util::{
+    future::{ok, FutureExt as _, LocalBoxFuture, Ready},
+    Stream,
+};
+use opentelemetry::{
+    baggage::BaggageExt as _,
+    global::{self, ObjectSafeTracerProvider},
+    propagation::{Extractor, Injector, TextMapPropagator},
+    trace::{
+        FutureExt as OtelFutureExt, Link, SamplingDecision, SamplingResult, Span, SpanBuilder,
+        SpanKind, SpanRef, Status, TraceContextExt, Tracer, TracerProvider,
+    },
+    Context, InstrumentationScope, Key, KeyValue, Value,
+};
+use opentelemetry_semantic_conventions::{
+    attribute::{HTTP_REQUEST_BODY_SIZE, THREAD_ID, THREAD_NAME, TLS_CIPHER, TLS_PROTOCOL_VERSION},
+    trace::{
+        CLIENT_ADDRESS, ERROR_TYPE, EXCEPTION_MESSAGE, EXCEPTION_TYPE, HTTP_RESPONSE_STATUS_CODE,
+        RPC_GRPC_STATUS_CODE, RPC_METHOD, RPC_SERVICE, RPC_SYSTEM, URL_QUERY, URL_SCHEME,
+    },
+};
+
+use super::{
+    filter::RequestFilter,
+    get_scope,
+    route_formatter::{BuiltinRouteFormatter, RouteFormatter},
+    UNMATCHED_ROUTE,
+};
+use crate::util::{
+    anonymize_ip, default_header_redactor, forwarded_for, forwarded_proto, header_attribute_keys,
+    header_attributes, server_trace_attributes, HeaderRedactor,
+};
+
+const HTTP_CONNECTION_TYPE: &str = "http.connection.type";
+const SAMPLING_PRIORITY: &str = "sampling.priority";
+const HTTP_REQUEST_MIME_TYPE: &str = "http.request.mime_type";
+const HTTP_RESPONSE_STATUS_TEXT: &str = "http.response.status_text";
+const HTTP_SERVER_DURATION_MS: &str = "http.server.duration_ms";
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+/// Request tracing middleware.
+///
+/// Span attributes are only built for recording spans, so requests that are
+/// not sampled, or traced while the global tracer provider is still the
+/// default no-op provider, only pay for context extraction and starting a
+/// non-recording span.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use actix_web::{web, App, HttpServer};
+/// use actix_web_opentelemetry::RequestTracing;
+/// use opentelemetry::global;
+/// use opentelemetry_sdk::trace::TracerProvider;
+///
+/// async fn index() -> &'static str {
+///     "Hello world!"
+/// }
+///
+/// #[actix_web::main]
+/// async fn main() -> std::io::Result<()> {
+///     // Install an OpenTelemetry trace pipeline.
+///     // Swap for https://docs.rs/opentelemetry-jaeger or other compatible
+///     // exporter to send trace information to your collector.
+///     let exporter = opentelemetry_stdout::SpanExporter::default();
+///
+///     // Configure your tracer provider with your exporter(s)
+///     let provider = TracerProvider::builder()
+///         .with_simple_exporter(exporter)
+///         .build();
+///     global::set_tracer_provider(provider);
+///
+///     HttpServer::new(|| {
+///         App::new()
+///             .wrap(RequestTracing::new())
+///             .service(web::resource("/").to(index))
+///     })
+///     .bind("127.0.0.1:8080")?
+///     .run()
+///     .await
+/// }
+///```
+#[derive(Default, Debug)]
+pub struct RequestTracing {
+    config: TracingConfig,
+}
+
+/// Formats a request extension as an attribute value.
+type ExtensionValue = fn(&Extensions) -> Option<String>;
+
+/// Options shared between [`RequestTracing`] and the middleware it builds.
+#[derive(Clone, Default, Debug)]
+struct TracingConfig {
+    route_formatter: Option<Rc<dyn RouteFormatter + 'static>>,
+    scope: Option<InstrumentationScope>,
+    tracer_provider: Option<SharedTracerProvider>,
+    filter: Option<RequestFilter>,
+    unmatched_route_strategy: UnmatchedRouteStrategy,
+    unmatched_route_label: Option<Cow<'static, str>>,
+    span_namer: Option<fn(&ServiceRequest) -> String>,
+    span_builder_hook: Option<fn(&ServiceRequest, &mut SpanBuilder)>,
+    status_mapper: Option<fn(StatusCode) -> Option<Status>>,
+    request_headers: Vec<(HeaderName, Key)>,
+    response_headers: Vec<(HeaderName, Key)>,
+    header_redactor: Option<HeaderRedactor>,
+    trace_response_header: Option<HeaderName>,
+    server_timing: bool,
+    slow_request_threshold: Option<Duration>,
+    duration_attribute: bool,
+    force_sample_header: Option<HeaderName>,
+    link_header: Option<HeaderName>,
+    skip_context_extraction: bool,
+    trust_forwarded_proto: bool,
+    forwarded_for_depth: Option<usize>,
+    ip_anonymization: bool,
+    server_address_source: ServerAddressSource,
+    propagators: Vec<Rc<dyn TextMapPropagator + 'static>>,
+    inject_context: bool,
+    context_attachment: bool,
+    path_params: bool,
+    path_param_names: Vec<String>,
+    default_attributes: Vec<KeyValue>,
+    baggage_attributes: Vec<Key>,
+    extension_attributes: Vec<(Key, ExtensionValue)>,
+    response_extension_attributes: Option<fn(&Extensions) -> Vec<KeyValue>>,
+    excluded_attributes: Vec<Key>,
+    measured_request_body: bool,
+    mime_type: bool,
+    max_attribute_length: Option<usize>,
+    worker_id: bool,
+    status_text: bool,
+    tls_attributes: bool,
+    grpc: bool,
+    skip_query_recording: bool,
+    query_redactor: Option<fn(&str) -> String>,
+    #[cfg(feature = "tracing")]
+    tracing_compat: bool,
+}
+
+/// Tracer provider set with [`RequestTracing::with_tracer_provider`].
+#[derive(Clone)]
+struct SharedTracerProvider(Rc<dyn AnyTracerProvider>);
+
+/// Tracer provider that can be downcast to its concrete type.
+trait AnyTracerProvider: ObjectSafeTracerProvider + Any {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<P: ObjectSafeTracerProvider + Any> AnyTracerProvider for P {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl fmt::Debug for SharedTracerProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedTracerProvider")
+    }
+}
+
+impl RequestTracing {
+    /// Actix web middleware to trace each request in an OpenTelemetry span.
+    pub fn new() -> RequestTracing {
+        RequestTracing::default()
+    }
+
+    /// Actix web middleware to trace each request in an OpenTelemetry span with
+    /// formatted routes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use actix_web::{web, App, HttpServer};
+    /// use actix_web_opentelemetry::{RouteFormatter, RequestTracing};
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() -> std::io::Result<()> {
+    ///
+    ///
+    /// #[derive(Debug)]
+    /// struct MyLowercaseFormatter;
+    ///
+    /// impl RouteFormatter for MyLowercaseFormatter {
+    ///     fn format(&self, path: &str) -> String {
+    ///         path.to_lowercase()
+    ///     }
+    /// }
+    ///
+    /// // report /users/{id} as /users/:id
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(RequestTracing::with_formatter(MyLowercaseFormatter))
+    ///         .service(web::resource("/users/{id}").to(|| async { "ok" }))
+    /// })
+    /// .bind("127.0.0.1:8080")?
+    /// .run()
+    /// .await
+    /// # }
+    /// ```
+    pub fn with_formatter<T: RouteFormatter + 'static>(route_formatter: T) -> Self {
+        let mut tracing = RequestTracing::default();
+        tracing.config.route_formatter = Some(Rc::new(route_formatter));
+        tracing
+    }
+
+    /// Format routes with one of the formatters provided by this crate.
+    ///
+    /// Replaces any formatter set with [`RequestTracing::with_formatter`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::{BuiltinRouteFormatter, RequestTracing};
+    ///
+    /// let tracing = RequestTracing::new().with_builtin_formatter(BuiltinRouteFormatter::All);
+    /// ```
+    pub fn with_builtin_formatter(mut self, formatter: BuiltinRouteFormatter) -> Self {
+        self.config.route_formatter = Some(Rc::new(formatter.formatter()));
+        self
+    }
+
+    /// Set the instrumentation scope of the tracer this middleware uses.
+    ///
+    /// Defaults to the `actix-web-opentelemetry` scope with the version of this
+    /// crate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::InstrumentationScope;
+    ///
+    /// let tracing = RequestTracing::new()
+    ///     .with_scope(InstrumentationScope::builder("my-sdk").with_version("1.0.0").build());
+    /// ```
+    pub fn with_scope(mut self, scope: InstrumentationScope) -> Self {
+        self.config.scope = Some(scope);
+        self
+    }
+
+    /// Create spans with tracers from the given tracer provider.
+    ///
+    /// Defaults to the global tracer provider set with
+    /// [`global::set_tracer_provider`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry_sdk::trace::TracerProvider;
+    ///
+    /// let provider = TracerProvider::builder().build();
+    /// let tracing = RequestTracing::new().with_tracer_provider(provider);
+    /// ```
+    pub fn with_tracer_provider<P, T, Sp>(mut self, tracer_provider: P) -> Self
+    where
+        Sp: Span + Send + Sync + 'static,
+        T: Tracer<Span = Sp> + Send + Sync + 'static,
+        P: TracerProvider<Tracer = T> + 'static,
+    {
+        self.config.tracer_provider = Some(SharedTracerProvider(Rc::new(tracer_provider)));
+        self
+    }
+
+    /// The tracer provider set with [`RequestTracing::with_tracer_provider`],
+    /// if it is of type `P`.
+    ///
+    /// Use it to flush pending spans on shutdown. SDK tracer providers are
+    /// handles to shared state, so keeping a clone of the provider passed to
+    /// [`RequestTracing::with_tracer_provider`] works as well, e.g. when the
+    /// middleware is created in the [`HttpServer`] app factory.
+    ///
+    /// [`HttpServer`]: actix_web::HttpServer
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry_sdk::trace::TracerProvider;
+    ///
+    /// let tracing = RequestTracing::new().with_tracer_provider(TracerProvider::builder().build());
+    ///
+    /// // on shutdown
+    /// if let Some(provider) = tracing.tracer_provider::<TracerProvider>() {
+    ///     for result in provider.force_flush() {
+    ///         result.expect("spans are flushed");
+    ///     }
+    /// }
+    /// # assert!(tracing.tracer_provider::<TracerProvider>().is_some());
+    /// # assert!(tracing.tracer_provider::<opentelemetry::global::GlobalTracerProvider>().is_none());
+    /// ```
+    pub fn tracer_provider<P: Any>(&self) -> Option<&P> {
+        self.config
+            .tracer_provider
+            .as_ref()
+            .and_then(|provider| provider.0.as_any().downcast_ref())
+    }
+
+    /// Only trace requests for which the given predicate returns `true`.
+    ///
+    /// Filtered requests are passed straight through to the inner service: no
+    /// parent context is extracted and no span is started, so excluded routes
+    /// such as health checks have no tracing overhead.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use actix_web::{web, App, HttpServer};
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(RequestTracing::new().with_filter(|req| {
+    ///             !matches!(req.path(), "/healthz" | "/readyz")
+    ///         }))
+    ///         .service(web::resource("/healthz").to(|| async { "ok" }))
+    /// })
+    /// .bind("127.0.0.1:8080")?
+    /// .run()
+    /// .await
+    /// # }
+    /// ```
+    pub fn with_filter(mut self, filter: fn(&ServiceRequest) -> bool) -> Self {
+        self.config.filter = Some(RequestFilter::new(filter));
+        self
+    }
+
+    /// Only trace requests selected by the given filter.
+    ///
+    /// Like [`RequestTracing::with_filter`], but the filter can be shared with
+    /// the `RequestMetrics` middleware.
+    pub fn with_request_filter(mut self, filter: RequestFilter) -> Self {
+        self.config.filter = Some(filter);
+        self
+    }
+
+    /// Choose the `http.route` recorded for requests that matched no route.
+    ///
+    /// Defaults to [`UnmatchedRouteStrategy::Default`]. The route formatter is
+    /// applied to the result as for matched routes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::{RequestTracing, UnmatchedRouteStrategy};
+    ///
+    /// let tracing = RequestTracing::new()
+    ///     .with_unmatched_route_strategy(UnmatchedRouteStrategy::RawPath);
+    /// ```
+    pub fn with_unmatched_route_strategy(mut self, strategy: UnmatchedRouteStrategy) -> Self {
+        self.config.unmatched_route_strategy = strategy;
+        self
+    }
+
+    /// Set the `http.route` recorded for requests that matched no route.
+    ///
+    /// Defaults to `default`. Only used with [`UnmatchedRouteStrategy::Default`].
+    pub fn with_unmatched_route_label(mut self, label: impl Into<Cow<'static, str>>) -> Self {
+        self.config.unmatched_route_label = Some(label.into());
+        self
+    }
+
+    /// Customise the span name, e.g. to include the request method.
+    ///
+    /// Spans are named after the (formatted) `http.route` by default.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use actix_web::{web, App, HttpServer};
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(RequestTracing::new().with_span_namer(|req| {
+    ///             format!(
+    ///                 "{} {}",
+    ///                 req.method(),
+    ///                 req.match_pattern().as_deref().unwrap_or("default")
+    ///             )
+    ///         }))
+    ///         .service(web::resource("/users/{id}").to(|| async { "ok" }))
+    /// })
+    /// .bind("127.0.0.1:8080")?
+    /// .run()
+    /// .await
+    /// # }
+    /// ```
+    pub fn with_span_namer(mut self, span_namer: fn(&ServiceRequest) -> String) -> Self {
+        self.config.span_namer = Some(span_namer);
+        self
+    }
+
+    /// Customise the span builder of each request before the span is started.
+    ///
+    /// The hook runs after all other options have been applied, so it can
+    /// override them, e.g. to add links or set the start time. The builder
+    /// holds the standard http attributes. Captured headers, path parameters,
+    /// baggage and other optional attributes are only built once the span has
+    /// started, and only for sampled spans, so they are not part of the
+    /// builder and not visible to samplers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::KeyValue;
+    ///
+    /// let tracing = RequestTracing::new().with_span_builder_hook(|req, builder| {
+    ///     if req.path().starts_with("/admin") {
+    ///         builder
+    ///             .attributes
+    ///             .get_or_insert_with(Vec::new)
+    ///             .push(KeyValue::new("admin", true));
+    ///     }
+    /// });
+    /// ```
+    pub fn with_span_builder_hook(
+        mut self,
+        span_builder_hook: fn(&ServiceRequest, &mut SpanBuilder),
+    ) -> Self {
+        self.config.span_builder_hook = Some(span_builder_hook);
+        self
+    }
+
+    /// Customise how response status codes map to span statuses.
+    ///
+    /// Returning `None` leaves the span status unset. By default only server
+    /// errors (`5xx`) are reported as errors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::http::StatusCode;
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::trace::Status;
+    ///
+    /// // also report rate limited requests as errors
+    /// let tracing = RequestTracing::new().with_status_mapper(|status| {
+    ///     if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+    ///         Some(Status::error(status.to_string()))
+    ///     } else {
+    ///         None
+    ///     }
+    /// });
+    /// ```
+    pub fn with_status_mapper(mut self, status_mapper: fn(StatusCode) -> Option<Status>) -> Self {
+        self.config.status_mapper = Some(status_mapper);
+        self
+    }
+
+    /// Record the given request headers as `http.request.header.<key>` span
+    /// attributes.
+    ///
+    /// Header names are matched case-insensitively and recorded with `-`
+    /// replaced by `_`, e.g. `X-Tenant-Id` is recorded as
+    /// `http.request.header.x_tenant_id`. Headers missing from the request are
+    /// omitted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new().with_request_headers(["x-tenant-id", "x-request-id"]);
+    /// ```
+    pub fn with_request_headers(
+        mut self,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.config.request_headers = header_attribute_keys("http.request.header", headers);
+        self
+    }
+
+    /// Record the given response headers as `http.response.header.<key>` span
+    /// attributes.
+    ///
+    /// Headers are named as in [`RequestTracing::with_request_headers`]. They
+    /// are read once the handler has produced a response, so they are not
+    /// available to samplers when the span is started.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new().with_response_headers(["content-type", "x-cache"]);
+    /// ```
+    pub fn with_response_headers(
+        mut self,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.config.response_headers = header_attribute_keys("http.response.header", headers);
+        self
+    }
+
+    /// Redact captured header values before they are recorded as span
+    /// attributes.
+    ///
+    /// Applies to headers captured with [`RequestTracing::with_request_headers`]
+    /// and [`RequestTracing::with_response_headers`]. Returning `None` drops the
+    /// value. `authorization`, `cookie`, `set-cookie` and `proxy-authorization`
+    /// are always dropped before the redactor runs, even if they are captured.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// // record only the last characters of api keys
+    /// let tracing = RequestTracing::new()
+    ///     .with_request_headers(["x-api-key", "x-tenant-id"])
+    ///     .with_header_redactor(|name, value| {
+    ///         let value = value.to_str().ok()?;
+    ///         if name == "x-api-key" {
+    ///             let suffix = value.get(value.len().saturating_sub(4)..)?;
+    ///             Some(format!("***{suffix}"))
+    ///         } else {
+    ///             Some(value.to_string())
+    ///         }
+    ///     });
+    /// ```
+    pub fn with_header_redactor(
+        mut self,
+        header_redactor: fn(&HeaderName, &HeaderValue) -> Option<String>,
+    ) -> Self {
+        self.config.header_redactor = Some(header_redactor);
+        self
+    }
+
+    /// Return the trace id of sampled requests in the given response header.
+    ///
+    /// Existing headers with the same name set by the handler are preserved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::http::header::HeaderName;
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new()
+    ///     .with_trace_response_header(HeaderName::from_static("x-trace-id"));
+    /// ```
+    pub fn with_trace_response_header(mut self, header: HeaderName) -> Self {
+        self.config.trace_response_header = Some(header);
+        self
+    }
+
+    /// Report the time spent handling the request in a `Server-Timing`
+    /// response header, e.g. `Server-Timing: app;dur=12.3`.
+    ///
+    /// The duration is in milliseconds and shown by browser developer tools.
+    /// `Server-Timing` headers set by the handler are preserved.
+    pub fn with_server_timing(mut self, server_timing: bool) -> Self {
+        self.config.server_timing = server_timing;
+        self
+    }
+
+    /// Add a `slow_request` event to spans of requests taking longer than the
+    /// given threshold.
+    ///
+    /// The event records the time spent handling the request in milliseconds
+    /// as `duration_ms`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use std::time::Duration;
+    ///
+    /// let tracing = RequestTracing::new().with_slow_request_event(Duration::from_secs(2));
+    /// ```
+    pub fn with_slow_request_event(mut self, threshold: Duration) -> Self {
+        self.config.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Record the time spent handling the request in milliseconds as the
+    /// `http.server.duration_ms` span attribute.
+    ///
+    /// Useful for backends that cannot sort spans by their duration. Disabled
+    /// by default.
+    pub fn with_duration_attribute(mut self, duration_attribute: bool) -> Self {
+        self.config.duration_attribute = duration_attribute;
+        self
+    }
+
+    /// Sample requests carrying the given header regardless of the configured
+    /// sampler.
+    ///
+    /// The header must be set to `1` or `true`. Forced spans are recorded with
+    /// a `sampling.priority` attribute of `1`. Only enable this for headers
+    /// your edge strips from untrusted requests, as forced sampling bypasses
+    /// any rate limiting done by the sampler.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::http::header::HeaderName;
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new()
+    ///     .with_force_sample_header(HeaderName::from_static("x-force-sample"));
+    /// ```
+    pub fn with_force_sample_header(mut self, header: HeaderName) -> Self {
+        self.config.force_sample_header = Some(header);
+        self
+    }
+
+    /// Link server spans to the span context propagated in the given header.
+    ///
+    /// The header holds a single propagation header value, e.g. a W3C
+    /// `traceparent`, which is parsed with the configured propagator and added
+    /// as a link instead of a parent. No link is added if the header is absent
+    /// or cannot be parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::http::header::HeaderName;
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// // link requests to the trace of the batch job that made them
+    /// let tracing = RequestTracing::new()
+    ///     .with_link_header(HeaderName::from_static("x-job-traceparent"));
+    /// ```
+    pub fn with_link_header(mut self, header: HeaderName) -> Self {
+        self.config.link_header = Some(header);
+        self
+    }
+
+    /// Whether to continue traces propagated by the incoming request headers.
+    ///
+    /// Enabled by default. When disabled, propagation headers are ignored and
+    /// every request starts a new root span, e.g. for services receiving
+    /// requests from untrusted callers.
+    pub fn with_extract_context(mut self, extract_context: bool) -> Self {
+        self.config.skip_context_extraction = !extract_context;
+        self
+    }
+
+    /// Record the scheme of the original client request as `url.scheme`
+    /// according to the `Forwarded` or `X-Forwarded-Proto` headers.
+    ///
+    /// Disabled by default. Enable it when running behind a TLS terminating
+    /// proxy that sets these headers, and strips them from client requests.
+    pub fn with_trust_forwarded_proto(mut self, trust_forwarded_proto: bool) -> Self {
+        self.config.trust_forwarded_proto = trust_forwarded_proto;
+        self
+    }
+
+    /// Record the `X-Forwarded-For` entry `depth` hops from the right as
+    /// `client.address`.
+    ///
+    /// Set `depth` to the number of proxies in front of the app that append to
+    /// the header, e.g. `1` selects the rightmost entry. Entries further left
+    /// can be forged by clients. The connection info address is recorded if the
+    /// header is absent or has fewer entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// // behind a CDN and a load balancer
+    /// let tracing = RequestTracing::new().with_forwarded_for_depth(2);
+    /// ```
+    pub fn with_forwarded_for_depth(mut self, depth: usize) -> Self {
+        self.config.forwarded_for_depth = Some(depth);
+        self
+    }
+
+    /// Mask the `client.address` and `network.peer.address` attributes.
+    ///
+    /// Disabled by default. When enabled, the last octet of IPv4 addresses and
+    /// the last 80 bits of IPv6 addresses are zeroed, e.g. `203.0.113.42` is
+    /// recorded as `203.0.113.0`.
+    pub fn with_ip_anonymization(mut self, ip_anonymization: bool) -> Self {
+        self.config.ip_anonymization = ip_anonymization;
+        self
+    }
+
+    /// Choose where the `server.address` and `server.port` attributes are
+    /// taken from.
+    ///
+    /// Defaults to [`ServerAddressSource::ConnectionInfo`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::{RequestTracing, ServerAddressSource};
+    ///
+    /// // record the virtual host requested by the client
+    /// let tracing =
+    ///     RequestTracing::new().with_server_address_source(ServerAddressSource::HostHeader);
+    /// ```
+    pub fn with_server_address_source(mut self, source: ServerAddressSource) -> Self {
+        self.config.server_address_source = source;
+        self
+    }
+
+    /// Extract propagated contexts with the given propagator instead of the
+    /// global one.
+    ///
+    /// Allows middlewares of different apps to accept different propagation
+    /// formats. The global text map propagator is used if not set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry_sdk::propagation::TraceContextPropagator;
+    ///
+    /// let tracing = RequestTracing::new().with_propagator(TraceContextPropagator::new());
+    /// ```
+    pub fn with_propagator(mut self, propagator: impl TextMapPropagator + 'static) -> Self {
+        self.config.propagators = vec![Rc::new(propagator)];
+        self
+    }
+
+    /// Extract propagated contexts with the first of the given propagators that
+    /// finds a valid span context.
+    ///
+    /// Propagators are tried in order, so when requests carry conflicting
+    /// headers, e.g. both `traceparent` and B3 headers, the earlier propagator
+    /// takes precedence. Unlike a [`TextMapCompositePropagator`], whose
+    /// propagators all extract into the same context and the last valid span
+    /// context wins, the contexts found by later propagators are ignored. If no
+    /// propagator finds a valid span context, the context extracted by the first
+    /// one is used, so it should also carry any baggage.
+    ///
+    /// The first propagator injects contexts into responses when
+    /// [`RequestTracing::with_inject_context`] is enabled. Replaces the
+    /// propagator set with [`RequestTracing::with_propagator`].
+    ///
+    /// [`TextMapCompositePropagator`]: opentelemetry::propagation::TextMapCompositePropagator
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::propagation::TextMapPropagator;
+    /// use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+    ///
+    /// let propagators: Vec<Box<dyn TextMapPropagator>> = vec![
+    ///     Box::new(TraceContextPropagator::new()),
+    ///     Box::new(BaggagePropagator::new()),
+    /// ];
+    /// let tracing = RequestTracing::new().with_propagators(propagators);
+    /// # use actix_web::{http::header::HeaderName, test, web, App};
+    /// # use opentelemetry::{
+    /// #     propagation::{text_map_propagator::FieldIter, Extractor, Injector},
+    /// #     trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+    /// #     Context,
+    /// # };
+    /// #
+    /// # // reads the trace id of a legacy `x-request-trace-id` header
+    /// # #[derive(Debug)]
+    /// # struct LegacyPropagator;
+    /// #
+    /// # impl TextMapPropagator for LegacyPropagator {
+    /// #     fn inject_context(&self, _cx: &Context, _injector: &mut dyn Injector) {}
+    /// #
+    /// #     fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+    /// #         match extractor
+    /// #             .get("x-request-trace-id")
+    /// #             .and_then(|id| TraceId::from_hex(id).ok())
+    /// #         {
+    /// #             Some(trace_id) => cx.with_remote_span_context(SpanContext::new(
+    /// #                 trace_id,
+    /// #                 SpanId::from_bytes([0, 0, 0, 0, 0, 0, 0, 1]),
+    /// #                 TraceFlags::SAMPLED,
+    /// #                 true,
+    /// #                 TraceState::default(),
+    /// #             )),
+    /// #             None => cx.clone(),
+    /// #         }
+    /// #     }
+    /// #
+    /// #     fn fields(&self) -> FieldIter<'_> {
+    /// #         FieldIter::new(&[])
+    /// #     }
+    /// # }
+    /// #
+    /// # const TRACEPARENT_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    /// # const LEGACY_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    /// #
+    /// # async fn response_trace_id(
+    /// #     propagators: Vec<Box<dyn TextMapPropagator>>,
+    /// #     headers: &[(&'static str, String)],
+    /// # ) -> String {
+    /// #     let app = test::init_service(
+    /// #         App::new()
+    /// #             .wrap(
+    /// #                 RequestTracing::new()
+    /// #                     .with_tracer_provider(opentelemetry_sdk::trace::TracerProvider::default())
+    /// #                     .with_propagators(propagators)
+    /// #                     .with_trace_response_header(HeaderName::from_static("x-trace-id")),
+    /// #             )
+    /// #             .route("/", web::get().to(|| async { "ok" })),
+    /// #     )
+    /// #     .await;
+    /// #     let mut req = test::TestRequest::get();
+    /// #     for header in headers {
+    /// #         req = req.insert_header(header.clone());
+    /// #     }
+    /// #     let res = test::call_service(&app, req.to_request()).await;
+    /// #     res.headers().get("x-trace-id").unwrap().to_str().unwrap().to_string()
+    /// # }
+    /// #
+    /// # actix_web::rt::System::new().block_on(async {
+    /// #     let conflicting = [
+    /// #         ("traceparent", format!("00-{TRACEPARENT_ID}-00f067aa0ba902b7-01")),
+    /// #         ("x-request-trace-id", LEGACY_ID.to_string()),
+    /// #     ];
+    /// #     let trace_id = response_trace_id(
+    /// #         vec![Box::new(TraceContextPropagator::new()), Box::new(LegacyPropagator)],
+    /// #         &conflicting,
+    /// #     )
+    /// #     .await;
+    /// #     assert_eq!(trace_id, TRACEPARENT_ID);
+    /// #
+    /// #     let trace_id = response_trace_id(
+    /// #         vec![Box::new(LegacyPropagator), Box::new(TraceContextPropagator::new())],
+    /// #         &conflicting,
+    /// #     )
+    /// #     .await;
+    /// #     assert_eq!(trace_id, LEGACY_ID);
+    /// #
+    /// #     // falls back to later propagators when earlier ones find no context
+    /// #     let trace_id = response_trace_id(
+    /// #         vec![Box::new(TraceContextPropagator::new()), Box::new(LegacyPropagator)],
+    /// #         &conflicting[1..],
+    /// #     )
+    /// #     .await;
+    /// #     assert_eq!(trace_id, LEGACY_ID);
+    /// # });
+    /// ```
+    pub fn with_propagators(
+        mut self,
+        propagators: impl IntoIterator<Item = Box<dyn TextMapPropagator + 'static>>,
+    ) -> Self {
+        self.config.propagators = propagators.into_iter().map(Rc::from).collect();
+        self
+    }
+
+    /// Inject the server span context into the response headers.
+    ///
+    /// Disabled by default. When enabled, the propagator set with
+    /// [`RequestTracing::with_propagator`] (or the global one) writes its
+    /// headers, e.g. `traceparent`, to successful responses so callers can link
+    /// their client spans to the server span. Headers with invalid names or
+    /// values are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new().with_inject_context(true);
+    /// # use actix_web::{test, web, App};
+    /// # use opentelemetry::{
+    /// #     propagation::{text_map_propagator::FieldIter, Extractor, Injector, TextMapPropagator},
+    /// #     Context,
+    /// # };
+    /// #
+    /// # #[derive(Debug)]
+    /// # struct InvalidPropagator;
+    /// #
+    /// # impl TextMapPropagator for InvalidPropagator {
+    /// #     fn inject_context(&self, _cx: &Context, injector: &mut dyn Injector) {
+    /// #         injector.set("invalid header", "value".to_string());
+    /// #         injector.set("x-invalid-value", "invalid\nvalue".to_string());
+    /// #         injector.set("x-valid", "value".to_string());
+    /// #     }
+    /// #
+    /// #     fn extract_with_context(&self, cx: &Context, _extractor: &dyn Extractor) -> Context {
+    /// #         cx.clone()
+    /// #     }
+    /// #
+    /// #     fn fields(&self) -> FieldIter<'_> {
+    /// #         FieldIter::new(&[])
+    /// #     }
+    /// # }
+    /// #
+    /// # actix_web::rt::System::new().block_on(async {
+    /// #     let app = test::init_service(
+    /// #         App::new()
+    /// #             .wrap(tracing.with_propagator(InvalidPropagator))
+    /// #             .route("/", web::get().to(|| async { "ok" })),
+    /// #     )
+    /// #     .await;
+    /// #     let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+    /// #     assert!(res.status().is_success());
+    /// #     assert!(!res.headers().contains_key("x-invalid-value"));
+    /// #     assert_eq!(res.headers().get("x-valid").unwrap(), "value");
+    /// # });
+    /// ```
+    pub fn with_inject_context(mut self, inject_context: bool) -> Self {
+        self.config.inject_context = inject_context;
+        self
+    }
+
+    /// Attach the server span context while the inner service is called.
+    ///
+    /// Makes the context available through [`Context::current`] to middlewares
+    /// and handlers doing synchronous work before returning a future, e.g. when
+    /// starting nested client requests. This is always enabled with the
+    /// `sync-middleware` feature.
+    pub fn with_context_attachment(mut self, context_attachment: bool) -> Self {
+        self.config.context_attachment = context_attachment;
+        self
+    }
+
+    /// Record matched path parameters as `http.route.param.<name>` span
+    /// attributes.
+    ///
+    /// Disabled by default as parameter values may contain personal data. See
+    /// [`RequestTracing::with_path_param_names`] to only record some parameters.
+    pub fn with_path_params(mut self, path_params: bool) -> Self {
+        self.config.path_params = path_params;
+        self
+    }
+
+    /// Record only the given matched path parameters as span attributes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// // for `/users/{id}/orders/{order_id}` only `http.route.param.order_id` is recorded
+    /// let tracing = RequestTracing::new().with_path_param_names(["order_id"]);
+    /// ```
+    pub fn with_path_param_names(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.path_params = true;
+        self.config.path_param_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add the given attributes to every server span.
+    ///
+    /// The standard http attributes take precedence over default attributes with
+    /// the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::KeyValue;
+    ///
+    /// let tracing = RequestTracing::new()
+    ///     .with_default_attributes([KeyValue::new("deployment.environment", "prod")]);
+    /// ```
+    pub fn with_default_attributes(mut self, attrs: impl IntoIterator<Item = KeyValue>) -> Self {
+        self.config.default_attributes.extend(attrs);
+        self
+    }
+
+    /// Record the given entries of the propagated baggage as span attributes.
+    ///
+    /// Entries are recorded under their baggage key and skipped if missing.
+    /// Baggage is only extracted if a baggage propagator is configured, e.g.
+    /// `opentelemetry_sdk::propagation::BaggagePropagator`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new().with_baggage_attributes(["account.tier", "feature.flag"]);
+    /// ```
+    pub fn with_baggage_attributes(
+        mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Self {
+        self.config.baggage_attributes = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Record the request extension of type `T` as the `key` attribute.
+    ///
+    /// The extension must be inserted by a middleware that runs before this
+    /// one, i.e. is registered after it, as attributes are recorded before the
+    /// request is passed on. Nothing is recorded for requests without the
+    /// extension.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{dev::Service as _, web, App, HttpMessage as _};
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use std::fmt;
+    ///
+    /// // name of the logical service handling a request
+    /// #[derive(Clone)]
+    /// struct ServiceName(&'static str);
+    ///
+    /// impl fmt::Display for ServiceName {
+    ///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    ///         f.write_str(self.0)
+    ///     }
+    /// }
+    ///
+    /// let app = App::new()
+    ///     .wrap(RequestTracing::new().with_attribute_from_extensions::<ServiceName>("app.service"))
+    ///     .wrap_fn(|req, srv| {
+    ///         let name = if req.path().starts_with("/billing") { "billing" } else { "accounts" };
+    ///         req.extensions_mut().insert(ServiceName(name));
+    ///         srv.call(req)
+    ///     })
+    ///     .route("/", web::get().to(|| async { "ok" }));
+    /// ```
+    pub fn with_attribute_from_extensions<T: fmt::Display + 'static>(
+        mut self,
+        key: impl Into<Key>,
+    ) -> Self {
+        self.config
+            .extension_attributes
+            .push((key.into(), extension_value::<T>));
+        self
+    }
+
+    /// Record span attributes computed from the response extensions.
+    ///
+    /// Lets handlers promote business outcomes onto the server span by storing
+    /// them in the extensions of the response they return. The function is
+    /// given the extensions rather than the response, as the middleware is
+    /// generic over the response body. It is called for responses returned by
+    /// the wrapped service, after the status code and response header
+    /// attributes are recorded and before the gRPC status and span status are
+    /// set. Not called for errors and panics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{dev::Extensions, web, App, HttpResponse};
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry::KeyValue;
+    ///
+    /// struct OrderStatus(&'static str);
+    ///
+    /// async fn create_order() -> HttpResponse {
+    ///     let mut res = HttpResponse::Created().finish();
+    ///     res.extensions_mut().insert(OrderStatus("pending"));
+    ///     res
+    /// }
+    ///
+    /// fn order_attributes(extensions: &Extensions) -> Vec<KeyValue> {
+    ///     extensions
+    ///         .get::<OrderStatus>()
+    ///         .map(|status| vec![KeyValue::new("order.status", status.0)])
+    ///         .unwrap_or_default()
+    /// }
+    ///
+    /// let app = App::new()
+    ///     .wrap(RequestTracing::new().with_response_extension_attributes(order_attributes))
+    ///     .route("/orders", web::post().to(create_order));
+    /// ```
+    pub fn with_response_extension_attributes(
+        mut self,
+        attributes: fn(&Extensions) -> Vec<KeyValue>,
+    ) -> Self {
+        self.config.response_extension_attributes = Some(attributes);
+        self
+    }
+
+    /// Do not record the given default server span attributes.
+    ///
+    /// Excluded attributes are skipped while the span attributes are built,
+    /// which reduces the size of high volume spans. All attributes are recorded
+    /// by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    /// use opentelemetry_semantic_conventions::trace::{URL_QUERY, USER_AGENT_ORIGINAL};
+    ///
+    /// let tracing = RequestTracing::new().without_attributes([USER_AGENT_ORIGINAL, URL_QUERY]);
+    /// ```
+    pub fn without_attributes(mut self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Self {
+        self.config.excluded_attributes = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Count the request body bytes read by the handler and record them as
+    /// `http.request.body.size`.
+    ///
+    /// Unlike the `Content-Length` based attribute this also measures chunked
+    /// requests, at the cost of wrapping the request payload. Bodies the handler
+    /// does not read are not counted.
+    pub fn with_measured_request_body(mut self, measured_request_body: bool) -> Self {
+        self.config.measured_request_body = measured_request_body;
+        self
+    }
+
+    /// Record the media type of the request `Content-Type` header as
+    /// `http.request.mime_type`.
+    ///
+    /// Parameters such as `charset` are dropped and the media type is
+    /// lowercased, e.g. `Application/JSON; charset=utf-8` is recorded as
+    /// `application/json`. Disabled by default.
+    pub fn with_mime_type(mut self, mime_type: bool) -> Self {
+        self.config.mime_type = mime_type;
+        self
+    }
+
+    /// Truncate string request attributes longer than `max_length` chars.
+    ///
+    /// Truncated values, e.g. of `user_agent.original`, `url.path` or
+    /// `url.query`, keep their first `max_length` chars followed by `…`. This
+    /// bounds the span size of requests with pathological headers or URLs.
+    /// Response attributes are not truncated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// let tracing = RequestTracing::new().with_max_attribute_length(1024);
+    /// ```
+    pub fn with_max_attribute_length(mut self, max_length: usize) -> Self {
+        self.config.max_attribute_length = Some(max_length);
+        self
+    }
+
+    /// Whether to record the request query string as `url.query`.
+    ///
+    /// Enabled by default. Disable it if query strings may contain personal
+    /// data or credentials, or see [`RequestTracing::with_query_redactor`] to
+    /// only scrub some parameters.
+    pub fn with_query_recording(mut self, query_recording: bool) -> Self {
+        self.config.skip_query_recording = !query_recording;
+        self
+    }
 
-use actix_web::{
-    dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    http::header::HeaderMap,
-    Error,
-};
-use futures_util::future::{ok, FutureExt as _, LocalBoxFuture, Ready};
-use opentelemetry::{
-    global::{self},
-    propagation::Extractor,
-    trace::{FutureExt as OtelFutureExt, SpanKind, Status, TraceContextExt, Tracer},
-    KeyValue,
-};
-use opentelemetry_semantic_conventions::trace::HTTP_RESPONSE_STATUS_CODE;
+    /// Record the request query string as `url.query` after passing it through
+    /// the given function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// // replace the value of `token` parameters
+    /// let tracing = RequestTracing::new().with_query_redactor(|query| {
+    ///     query
+    ///         .split('&')
+    ///         .map(|param| match param.split_once('=') {
+    ///             Some(("token", _)) => "token=REDACTED",
+    ///             _ => param,
+    ///         })
+    ///         .collect::<Vec<_>>()
+    ///         .join("&")
+    /// });
+    /// ```
+    pub fn with_query_redactor(mut self, query_redactor: fn(&str) -> String) -> Self {
+        self.config.query_redactor = Some(query_redactor);
+        self
+    }
 
-use super::{get_scope, route_formatter::RouteFormatter};
-use crate::util::trace_attributes_from_request;
+    /// Create request spans with the `tracing` crate instead of the
+    /// OpenTelemetry SDK.
+    ///
+    /// Disabled by default. When enabled, each request is traced by a `tracing`
+    /// span annotated with `otel.name`, `otel.kind` and `otel.status_code`
+    /// fields, so spans created by `#[tracing::instrument]` handlers are its
+    /// children. Exporting it requires a [`tracing-opentelemetry`] layer, which
+    /// parents it to the propagated context as that context is current while
+    /// the span is created.
+    ///
+    /// No OpenTelemetry span is started, so only the method, route, path,
+    /// scheme and response status are recorded, and options working on the
+    /// OpenTelemetry span such as header capture, response context injection
+    /// or exception events are ignored. Prefer the default SDK spans unless
+    /// handlers are instrumented with `tracing`.
+    ///
+    /// [`tracing-opentelemetry`]: https://docs.rs/tracing-opentelemetry
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_web::{web, App};
+    /// use actix_web_opentelemetry::RequestTracing;
+    ///
+    /// #[tracing::instrument]
+    /// async fn index() -> &'static str {
+    ///     "Hello world!"
+    /// }
+    ///
+    /// let app = App::new()
+    ///     .wrap(RequestTracing::new().with_tracing_compat(true))
+    ///     .route("/", web::get().to(index));
+    /// # actix_web::rt::System::new().block_on(async {
+    /// #     let app = actix_web::test::init_service(app).await;
+    /// #     let req = actix_web::test::TestRequest::get().to_request();
+    /// #     let res = actix_web::test::call_service(&app, req).await;
+    /// #     assert!(res.status().is_success());
+    /// # });
+    /// ```
+    #[cfg(feature = "tracing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+    pub fn with_tracing_compat(mut self, tracing_compat: bool) -> Self {
+        self.config.tracing_compat = tracing_compat;
+        self
+    }
 
-/// Request tracing middleware.
-///
-/// # Examples:
-///
-/// ```no_run
-/// use actix_web::{web, App, HttpServer};
-/// use actix_web_opentelemetry::RequestTracing;
-/// use opentelemetry::global;
-/// use opentelemetry_sdk::trace::TracerProvider;
-///
-/// async fn index() -> &'static str {
-///     "Hello world!"
-/// }
-///
-/// #[actix_web::main]
-/// async fn main() -> std::io::Result<()> {
-///     // Install an OpenTelemetry trace pipeline.
-///     // Swap for https://docs.rs/opentelemetry-jaeger or other compatible
-///     // exporter to send trace information to your collector.
-///     let exporter = opentelemetry_stdout::SpanExporter::default();
-///
-///     // Configure your tracer provider with your exporter(s)
-///     let provider = TracerProvider::builder()
-///         .with_simple_exporter(exporter)
-///         .build();
-///     global::set_tracer_provider(provider);
-///
-///     HttpServer::new(|| {
-///         App::new()
-///             .wrap(RequestTracing::new())
-///             .service(web::resource("/").to(index))
-///     })
-///     .bind("127.0.0.1:8080")?
-///     .run()
-///     .await
-/// }
-///```
-#[derive(Default, Debug)]
-pub struct RequestTracing {
-    route_formatter: Option<Rc<dyn RouteFormatter + 'static>>,
-}
+    /// Follow the gRPC semantic conventions for gRPC requests.
+    ///
+    /// Requests with an `application/grpc` content type are named
+    /// `{service}/{method}` after their path and record the `rpc.system`,
+    /// `rpc.service` and `rpc.method` attributes in addition to the http
+    /// attributes. The `grpc-status` response header is recorded as
+    /// `rpc.grpc.status_code` and determines the span status, falling back to
+    /// the http status if the status is only sent in trailers. Other requests
+    /// are traced as usual.
+    pub fn with_grpc(mut self, grpc: bool) -> Self {
+        self.config.grpc = grpc;
+        self
+    }
 
-impl RequestTracing {
-    /// Actix web middleware to trace each request in an OpenTelemetry span.
-    pub fn new() -> RequestTracing {
-        RequestTracing::default()
+    /// Record the worker thread handling the request as `thread.id` and
+    /// `thread.name` span attributes.
+    ///
+    /// Actix names its worker threads after their arbiter, e.g.
+    /// `actix-rt|system:0|arbiter:2`, which helps to attribute latency spikes
+    /// to a single busy worker. Disabled by default.
+    pub fn with_worker_id(mut self, worker_id: bool) -> Self {
+        self.config.worker_id = worker_id;
+        self
     }
 
-    /// Actix web middleware to trace each request in an OpenTelemetry span with
-    /// formatted routes.
+    /// Record the canonical reason of the response status, e.g. `Not Found`, as
+    /// a `http.response.status_text` span attribute.
     ///
-    /// # Examples
+    /// Eases migrating dashboards built on the `http.status_text` attribute of
+    /// older versions of this crate. Disabled by default.
+    pub fn with_status_text(mut self, status_text: bool) -> Self {
+        self.config.status_text = status_text;
+        self
+    }
+
+    /// Record the `tls.protocol.version` and `tls.cipher` span attributes of
+    /// requests served over TLS.
     ///
-    /// ```no_run
-    /// use actix_web::{web, App, HttpServer};
-    /// use actix_web_opentelemetry::{RouteFormatter, RequestTracing};
+    /// Actix does not expose the TLS session of a connection, so the attributes
+    /// are read from the [`TlsInfo`] connection data set in
+    /// [`HttpServer::on_connect`]. Requests without it, e.g. plaintext requests
+    /// or requests over TLS terminated by a proxy, are recorded without them.
+    /// Disabled by default.
     ///
-    /// # #[actix_web::main]
-    /// # async fn main() -> std::io::Result<()> {
+    /// [`HttpServer::on_connect`]: actix_web::HttpServer::on_connect
     ///
+    /// # Examples
     ///
-    /// #[derive(Debug)]
-    /// struct MyLowercaseFormatter;
+    /// ```no_run
+    /// use actix_web::{App, HttpServer};
+    /// use actix_web_opentelemetry::{RequestTracing, TlsInfo};
+    /// use std::any::Any;
     ///
-    /// impl RouteFormatter for MyLowercaseFormatter {
-    ///     fn format(&self, path: &str) -> String {
-    ///         path.to_lowercase()
-    ///     }
+    /// fn tls_info(conn: &dyn Any) -> Option<TlsInfo> {
+    ///     // downcast to the stream type of your TLS acceptor, e.g.
+    ///     // `actix_tls::accept::rustls_0_23::TlsStream<TcpStream>`, and read the
+    ///     // negotiated protocol version and cipher suite from its session
+    ///     # let _ = conn;
+    ///     None
     /// }
     ///
-    /// // report /users/{id} as /users/:id
-    /// HttpServer::new(move || {
-    ///     App::new()
-    ///         .wrap(RequestTracing::with_formatter(MyLowercaseFormatter))
-    ///         .service(web::resource("/users/{id}").to(|| async { "ok" }))
-    /// })
-    /// .bind("127.0.0.1:8080")?
-    /// .run()
-    /// .await
+    /// # #[actix_web::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// HttpServer::new(|| App::new().wrap(RequestTracing::new().with_tls_attributes(true)))
+    ///     .on_connect(|conn, data| {
+    ///         if let Some(tls_info) = tls_info(conn) {
+    ///             data.insert(tls_info);
+    ///         }
+    ///     })
+    ///     .bind("127.0.0.1:8080")?
+    ///     .run()
+    ///     .await
     /// # }
     /// ```
-    pub fn with_formatter<T: RouteFormatter + 'static>(route_formatter: T) -> Self {
-        RequestTracing {
-            route_formatter: Some(Rc::new(route_formatter)),
+    pub fn with_tls_attributes(mut self, tls_attributes: bool) -> Self {
+        self.config.tls_attributes = tls_attributes;
+        self
+    }
+}
+
+/// TLS session details of a connection, see
+/// [`RequestTracing::with_tls_attributes`].
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+    /// The negotiated protocol version, e.g. `1.3`.
+    pub protocol_version: Cow<'static, str>,
+    /// The negotiated cipher suite, e.g. `TLS_AES_128_GCM_SHA256`.
+    pub cipher: Cow<'static, str>,
+}
+
+/// The `http.route` recorded for requests that matched no route, e.g. `404`s.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum UnmatchedRouteStrategy {
+    /// Record the same label for every unmatched request, `default` unless set
+    /// with [`RequestTracing::with_unmatched_route_label`].
+    #[default]
+    Default,
+    /// Record the request path. Only suitable for services with few distinct
+    /// paths, as scanners can create an unbounded number of routes.
+    RawPath,
+    /// Record the result of the given function.
+    Custom(fn(&ServiceRequest) -> String),
+}
+
+impl UnmatchedRouteStrategy {
+    fn route(&self, req: &ServiceRequest, label: Option<&Cow<'static, str>>) -> Cow<'static, str> {
+        match self {
+            UnmatchedRouteStrategy::Default => label.cloned().unwrap_or(UNMATCHED_ROUTE.into()),
+            UnmatchedRouteStrategy::RawPath => req.path().to_string().into(),
+            UnmatchedRouteStrategy::Custom(route) => route(req).into(),
         }
     }
 }
 
+/// Request extension marking the server span as ended by the handler.
+///
+/// By default the span ends once the response is returned. Handlers that keep
+/// working on a request after responding can insert this marker into the
+/// request extensions and keep a clone of the current [`Context`], which owns
+/// the span. The span then ends when the handler calls [`Span::end`] on it, or
+/// once the last clone of the context is dropped. Spans of failed requests are
+/// always ended by the middleware, and the `http.server.duration_ms` attribute
+/// is not recorded for deferred spans.
+///
+/// [`Span::end`]: opentelemetry::trace::Span::end
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{rt, web, App, HttpMessage as _, HttpRequest, HttpResponse};
+/// use actix_web_opentelemetry::{DeferSpanEnd, RequestTracing};
+/// use opentelemetry::{trace::TraceContextExt as _, Context};
+///
+/// async fn checkout(req: HttpRequest) -> HttpResponse {
+///     req.extensions_mut().insert(DeferSpanEnd);
+///     let cx = Context::current();
+///     rt::spawn(async move {
+///         // finalize the order within the request span
+///         cx.span().add_event("order.finalized", vec![]);
+///         cx.span().end();
+///     });
+///     HttpResponse::Accepted().finish()
+/// }
+///
+/// let app = App::new()
+///     .wrap(RequestTracing::new())
+///     .route("/checkout", web::post().to(checkout));
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeferSpanEnd;
+
+/// The source of the `server.address` and `server.port` span attributes.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ServerAddressSource {
+    /// The host reported by actix's connection info, which prefers the
+    /// `Forwarded` and `X-Forwarded-Host` headers and may describe a proxy.
+    #[default]
+    ConnectionInfo,
+    /// The literal `Host` header sent by the client, falling back to the
+    /// connection info for requests without one, e.g. HTTP/2 requests.
+    HostHeader,
+}
+
 impl<S, B> Transform<S, ServiceRequest> for RequestTracing
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
@@ -118,10 +1415,15 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
+        let scope = self.config.scope.clone().unwrap_or_else(get_scope);
+        let tracer = match &self.config.tracer_provider {
+            Some(provider) => global::BoxedTracer::new(provider.0.boxed_tracer(scope)),
+            None => global::tracer_with_scope(scope),
+        };
         ok(RequestTracingMiddleware::new(
-            global::tracer_with_scope(get_scope()),
+            tracer,
             service,
-            self.route_formatter.clone(),
+            Rc::new(self.config.clone()),
         ))
     }
 }
@@ -131,7 +1433,7 @@ where
 pub struct RequestTracingMiddleware<S> {
     tracer: global::BoxedTracer,
     service: S,
-    route_formatter: Option<Rc<dyn RouteFormatter>>,
+    config: Rc<TracingConfig>,
 }
 
 impl<S, B> RequestTracingMiddleware<S>
@@ -140,15 +1442,11 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    fn new(
-        tracer: global::BoxedTracer,
-        service: S,
-        route_formatter: Option<Rc<dyn RouteFormatter>>,
-    ) -> Self {
+    fn new(tracer: global::BoxedTracer, service: S, config: Rc<TracingConfig>) -> Self {
         RequestTracingMiddleware {
             tracer,
             service,
-            route_formatter,
+            config,
         }
     }
 }
@@ -168,65 +1466,615 @@ where
     }
 
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
-        let parent_context = global::get_text_map_propagator(|propagator| {
-            propagator.extract(&RequestHeaderCarrier::new(req.headers_mut()))
-        });
-        let mut http_route: Cow<'static, str> = req
-            .match_pattern()
-            .map(Into::into)
-            .unwrap_or_else(|| "default".into());
-        if let Some(formatter) = &self.route_formatter {
-            http_route = formatter.format(&http_route).into();
-        }
-
-        let mut builder = self.tracer.span_builder(http_route.clone());
+        if let Some(filter) = &self.config.filter {
+            if !filter.matches(&req) {
+                return Box::pin(self.service.call(req));
+            }
+        }
+        let start = Instant::now();
+
+        let parent_context = if self.config.skip_context_extraction {
+            Context::new()
+        } else if !self.config.propagators.is_empty() {
+            extract_context(
+                &self.config.propagators,
+                &RequestHeaderCarrier::new(req.headers_mut()),
+            )
+        } else {
+            global::get_text_map_propagator(|propagator| {
+                propagator.extract(&RequestHeaderCarrier::new(req.headers_mut()))
+            })
+        };
+        let mut http_route: Cow<'static, str> =
+            req.match_pattern().map(Into::into).unwrap_or_else(|| {
+                self.config
+                    .unmatched_route_strategy
+                    .route(&req, self.config.unmatched_route_label.as_ref())
+            });
+        if let Some(formatter) = &self.config.route_formatter {
+            http_route = formatter.format_request(&req, &http_route).into();
+        }
+
+        let grpc_method = self.config.grpc.then(|| grpc_method(&req)).flatten();
+        let span_name = match (self.config.span_namer, &grpc_method) {
+            (Some(span_namer), _) => span_namer(&req).into(),
+            (None, Some((service, method))) => format!("{service}/{method}").into(),
+            (None, None) => http_route.clone(),
+        };
+
+        #[cfg(feature = "tracing")]
+        if self.config.tracing_compat {
+            // `tracing-opentelemetry` parents spans without a `tracing` parent to
+            // the current context
+            let attachment = parent_context.attach();
+            let tracing_span = tracing::info_span!(
+                target: env!("CARGO_PKG_NAME"),
+                "HTTP request",
+                otel.name = %span_name,
+                otel.kind = "server",
+                otel.status_code = tracing::field::Empty,
+                http.request.method = %req.method(),
+                http.route = %http_route,
+                url.path = req.path(),
+                url.scheme = %req.connection_info().scheme(),
+                http.response.status_code = tracing::field::Empty,
+            );
+            drop(attachment);
+
+            let fut = tracing_span.in_scope(|| self.service.call(req));
+            let span = tracing_span.clone();
+            let status_mapper = self.config.status_mapper.unwrap_or(default_status_mapper);
+            let fut = fut.map(move |res| {
+                let status = match &res {
+                    Ok(res) => res.status(),
+                    Err(err) => err.as_response_error().status_code(),
+                };
+                span.record("http.response.status_code", status.as_u16());
+                match (&res, status_mapper(status)) {
+                    (Err(_), _) | (_, Some(Status::Error { .. })) => {
+                        span.record("otel.status_code", "ERROR");
+                    }
+                    (_, Some(Status::Ok)) => {
+                        span.record("otel.status_code", "OK");
+                    }
+                    _ => {}
+                }
+                res
+            });
+            return Box::pin(tracing::Instrument::instrument(fut, tracing_span));
+        }
+
+        let mut builder = self.tracer.span_builder(span_name);
         builder.span_kind = Some(SpanKind::Server);
-        builder.attributes = Some(trace_attributes_from_request(&req, &http_route));
+        let is_grpc = grpc_method.is_some();
+        builder.attributes = Some(request_attributes(
+            &self.config,
+            &req,
+            &http_route,
+            grpc_method,
+        ));
+        if let Some(header) = &self.config.force_sample_header {
+            if is_truthy_header(req.headers(), header) {
+                builder.sampling_result = Some(SamplingResult {
+                    decision: SamplingDecision::RecordAndSample,
+                    attributes: vec![KeyValue::new(SAMPLING_PRIORITY, 1)],
+                    trace_state: parent_context.span().span_context().trace_state().clone(),
+                });
+            }
+        }
+        if let Some(value) = self
+            .config
+            .link_header
+            .as_ref()
+            .and_then(|header| req.headers().get(header))
+            .and_then(|value| value.to_str().ok())
+        {
+            let carrier = LinkHeaderCarrier { value };
+            let link_context = if self.config.propagators.is_empty() {
+                global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+            } else {
+                extract_context(&self.config.propagators, &carrier)
+            };
+            let span_context = link_context.span().span_context().clone();
+            if span_context.is_valid() {
+                builder
+                    .links
+                    .get_or_insert_with(Vec::new)
+                    .push(Link::with_context(span_context));
+            }
+        }
+        if let Some(span_builder_hook) = self.config.span_builder_hook {
+            span_builder_hook(&req, &mut builder);
+        }
 
-        let span = self.tracer.build_with_context(builder, &parent_context);
+        let mut span = self.tracer.build_with_context(builder, &parent_context);
+        // skip building optional attributes for spans that are not sampled
+        if span.is_recording() {
+            span.set_attributes(optional_request_attributes(
+                &self.config,
+                &req,
+                &parent_context,
+            ));
+        }
         let cx = parent_context.with_span(span);
 
-        #[cfg(feature = "sync-middleware")]
-        let attachment = cx.clone().attach();
+        let request_body_size = if self.config.measured_request_body {
+            let size = Rc::new(Cell::new(0));
+            let payload: BoxedPayloadStream = Box::pin(CountingPayload {
+                payload: req.take_payload(),
+                size: size.clone(),
+            });
+            req.set_payload(Payload::Stream { payload });
+            Some(size)
+        } else {
+            None
+        };
+
+        let attachment = (cfg!(feature = "sync-middleware") || self.config.context_attachment)
+            .then(|| cx.clone().attach());
 
-        let fut = self
-            .service
-            .call(req)
-            .with_context(cx.clone())
+        // entered for the synchronous call below and every poll of the response future
+        #[cfg(feature = "tracing")]
+        let tracing_span = {
+            let span_context = cx.span().span_context().clone();
+            tracing::info_span!(
+                target: env!("CARGO_PKG_NAME"),
+                "request",
+                trace_id = %span_context.trace_id(),
+                span_id = %span_context.span_id(),
+            )
+        };
+        #[cfg(feature = "tracing")]
+        let entered = tracing_span.enter();
+
+        let config = self.config.clone();
+        let fut = AssertUnwindSafe(self.service.call(req).with_context(cx.clone()))
+            .catch_unwind()
             .map(move |res| match res {
-                Ok(ok_res) => {
+                Ok(Ok(mut ok_res)) => {
                     let span = cx.span();
+                    if let Some(size) = request_body_size {
+                        span.set_attribute(KeyValue::new(HTTP_REQUEST_BODY_SIZE, size.get()));
+                    }
                     span.set_attribute(KeyValue::new(
                         HTTP_RESPONSE_STATUS_CODE,
                         ok_res.status().as_u16() as i64,
                     ));
+                    if let Some(reason) = ok_res
+                        .status()
+                        .canonical_reason()
+                        .filter(|_| config.status_text)
+                    {
+                        span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_TEXT, reason));
+                    }
                     if ok_res.status().is_server_error() {
-                        span.set_status(Status::error(
-                            ok_res
-                                .status()
-                                .canonical_reason()
-                                .map(ToString::to_string)
-                                .unwrap_or_default(),
+                        span.set_attribute(KeyValue::new(
+                            ERROR_TYPE,
+                            ok_res.status().as_str().to_string(),
                         ));
-                    };
-                    span.end();
+                    }
+                    if is_websocket_upgrade(ok_res.status(), ok_res.headers()) {
+                        // the span only covers the handshake, not the websocket connection
+                        span.set_attribute(KeyValue::new(HTTP_CONNECTION_TYPE, "websocket"));
+                        span.add_event("websocket.upgrade", Vec::new());
+                    }
+                    if !config.response_headers.is_empty() {
+                        let mut attributes = Vec::with_capacity(config.response_headers.len());
+                        header_attributes(
+                            ok_res.headers(),
+                            &config.response_headers,
+                            config.header_redactor.unwrap_or(default_header_redactor),
+                            &mut attributes,
+                        );
+                        span.set_attributes(attributes);
+                    }
+                    if let Some(response_attributes) = config.response_extension_attributes {
+                        span.set_attributes(response_attributes(&ok_res.response().extensions()));
+                    }
+                    if let Some(header) = &config.trace_response_header {
+                        let span_context = span.span_context();
+                        if span_context.is_valid()
+                            && span_context.is_sampled()
+                            && !ok_res.headers().contains_key(header)
+                        {
+                            if let Ok(value) =
+                                HeaderValue::from_str(&span_context.trace_id().to_string())
+                            {
+                                ok_res.headers_mut().insert(header.clone(), value);
+                            }
+                        }
+                    }
+                    let elapsed = start.elapsed();
+                    if config
+                        .slow_request_threshold
+                        .is_some_and(|threshold| elapsed > threshold)
+                    {
+                        span.add_event(
+                            "slow_request",
+                            vec![KeyValue::new("duration_ms", elapsed.as_millis() as i64)],
+                        );
+                    }
+                    if config.server_timing {
+                        let duration = elapsed.as_secs_f64() * 1000.0;
+                        if let Ok(value) = HeaderValue::from_str(&format!("app;dur={duration:.1}"))
+                        {
+                            ok_res.headers_mut().append(SERVER_TIMING, value);
+                        }
+                    }
+                    if config.inject_context {
+                        let mut carrier = ResponseHeaderCarrier::new(ok_res.headers_mut());
+                        match config.propagators.first() {
+                            Some(propagator) => propagator.inject_context(&cx, &mut carrier),
+                            None => global::get_text_map_propagator(|propagator| {
+                                propagator.inject_context(&cx, &mut carrier)
+                            }),
+                        }
+                    }
+                    let grpc_status = is_grpc.then(|| grpc_status(ok_res.headers())).flatten();
+                    if let Some(grpc_status) = grpc_status {
+                        span.set_attribute(KeyValue::new(RPC_GRPC_STATUS_CODE, grpc_status));
+                        if is_grpc_server_error(grpc_status) {
+                            span.set_status(Status::error(format!("gRPC status {grpc_status}")));
+                        }
+                    } else {
+                        let status_mapper = config.status_mapper.unwrap_or(default_status_mapper);
+                        if let Some(status) = status_mapper(ok_res.status()) {
+                            span.set_status(status);
+                        }
+                    }
+                    if !ok_res.request().extensions().contains::<DeferSpanEnd>() {
+                        end_span(&span, &config, start);
+                    }
                     Ok(ok_res)
                 }
-                Err(err) => {
+                Ok(Err(err)) => {
                     let span = cx.span();
+                    // the status of the rendered error, matching the request metrics
+                    span.set_attribute(KeyValue::new(
+                        ERROR_TYPE,
+                        err.as_response_error().status_code().as_str().to_string(),
+                    ));
+                    record_exception(&span, "actix_web::Error", err.to_string());
                     span.set_status(Status::error(format!("{:?}", err)));
-                    span.end();
+                    end_span(&span, &config, start);
                     Err(err)
                 }
+                Err(payload) => {
+                    let span = cx.span();
+                    let message = panic_message(payload.as_ref());
+                    span.set_attribute(KeyValue::new(ERROR_TYPE, "panic"));
+                    record_exception(&span, "panic", message.clone());
+                    span.set_status(Status::error(message));
+                    end_span(&span, &config, start);
+                    panic::resume_unwind(payload)
+                }
             });
 
-        #[cfg(feature = "sync-middleware")]
+        #[cfg(feature = "tracing")]
+        drop(entered);
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(fut, tracing_span);
+
         drop(attachment);
 
         Box::pin(fut)
     }
 }
 
+/// Extract the context of the first propagator that finds a valid span
+/// context, or the context of the first propagator if none does.
+fn extract_context(
+    propagators: &[Rc<dyn TextMapPropagator + 'static>],
+    extractor: &dyn Extractor,
+) -> Context {
+    let mut contexts = propagators.iter().map(|p| p.extract(extractor));
+    let first = contexts.next().unwrap_or_default();
+    if first.span().span_context().is_valid() {
+        return first;
+    }
+    contexts
+        .find(|cx| cx.span().span_context().is_valid())
+        .unwrap_or(first)
+}
+
+/// Build the standard server span attributes of a request, which are passed
+/// to samplers.
+fn request_attributes(
+    config: &TracingConfig,
+    req: &ServiceRequest,
+    http_route: &str,
+    grpc_method: Option<(String, String)>,
+) -> Vec<KeyValue> {
+    let mut attributes = server_trace_attributes(
+        req,
+        http_route,
+        &config.excluded_attributes,
+        config.ip_anonymization,
+        matches!(
+            config.server_address_source,
+            ServerAddressSource::HostHeader
+        ),
+    );
+    if let Some(client) = config
+        .forwarded_for_depth
+        .and_then(|depth| forwarded_for(req.headers(), depth))
+    {
+        let client = if config.ip_anonymization {
+            anonymize_ip(&client)
+        } else {
+            client
+        };
+        if let Some(kv) = attributes
+            .iter_mut()
+            .find(|kv| kv.key.as_str() == CLIENT_ADDRESS)
+        {
+            kv.value = client.into();
+        } else if !config
+            .excluded_attributes
+            .iter()
+            .any(|k| k.as_str() == CLIENT_ADDRESS)
+        {
+            attributes.push(KeyValue::new(CLIENT_ADDRESS, client));
+        }
+    }
+    if config.trust_forwarded_proto {
+        if let Some(proto) = forwarded_proto(req.headers()) {
+            if let Some(kv) = attributes
+                .iter_mut()
+                .find(|kv| kv.key.as_str() == URL_SCHEME)
+            {
+                kv.value = proto.into();
+            }
+        }
+    }
+    if config.skip_query_recording {
+        attributes.retain(|kv| kv.key.as_str() != URL_QUERY);
+    } else if let (Some(query_redactor), Some(query)) = (config.query_redactor, req.uri().query()) {
+        if let Some(kv) = attributes
+            .iter_mut()
+            .find(|kv| kv.key.as_str() == URL_QUERY)
+        {
+            kv.value = query_redactor(query).into();
+        }
+    }
+    if let Some((service, method)) = grpc_method {
+        attributes.push(KeyValue::new(RPC_SYSTEM, "grpc"));
+        attributes.push(KeyValue::new(RPC_SERVICE, service));
+        attributes.push(KeyValue::new(RPC_METHOD, method));
+    }
+    let default_attributes: Vec<KeyValue> = config
+        .default_attributes
+        .iter()
+        .filter(|default| !attributes.iter().any(|kv| kv.key == default.key))
+        .cloned()
+        .collect();
+    attributes.extend(default_attributes);
+    truncate_attributes(config, &mut attributes);
+    attributes
+}
+
+/// Build the optional server span attributes of a request, which are only
+/// recorded for sampled spans.
+fn optional_request_attributes(
+    config: &TracingConfig,
+    req: &ServiceRequest,
+    parent_context: &Context,
+) -> Vec<KeyValue> {
+    let mut attributes = Vec::new();
+    let header_redactor = config.header_redactor.unwrap_or(default_header_redactor);
+    header_attributes(
+        req.headers(),
+        &config.request_headers,
+        header_redactor,
+        &mut attributes,
+    );
+    if config.path_params {
+        path_param_attributes(config, req, &mut attributes);
+    }
+    if config.tls_attributes {
+        if let Some(tls_info) = req.conn_data::<TlsInfo>() {
+            attributes.push(KeyValue::new(
+                TLS_PROTOCOL_VERSION,
+                tls_info.protocol_version.clone(),
+            ));
+            attributes.push(KeyValue::new(TLS_CIPHER, tls_info.cipher.clone()));
+        }
+    }
+    if config.mime_type {
+        if let Some(mime_type) = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(str::trim)
+            .filter(|mime_type| !mime_type.is_empty())
+        {
+            attributes.push(KeyValue::new(
+                HTTP_REQUEST_MIME_TYPE,
+                mime_type.to_ascii_lowercase(),
+            ));
+        }
+    }
+    if config.worker_id {
+        let thread = std::thread::current();
+        attributes.push(KeyValue::new(THREAD_ID, format!("{:?}", thread.id())));
+        if let Some(name) = thread.name() {
+            attributes.push(KeyValue::new(THREAD_NAME, name.to_string()));
+        }
+    }
+    if !config.extension_attributes.is_empty() {
+        let extensions = req.extensions();
+        for (key, extension_value) in &config.extension_attributes {
+            if let Some(value) = extension_value(&extensions) {
+                attributes.push(KeyValue::new(key.clone(), value));
+            }
+        }
+    }
+    let baggage = parent_context.baggage();
+    for key in &config.baggage_attributes {
+        if let Some(value) = baggage.get(key) {
+            attributes.push(KeyValue::new(key.clone(), value.clone()));
+        }
+    }
+    truncate_attributes(config, &mut attributes);
+    attributes
+}
+
+/// Record the parameters of the matched route.
+///
+/// Requests are routed after app level middleware runs, so the parameters
+/// are captured from the match pattern instead of [`ServiceRequest::match_info`].
+fn path_param_attributes(
+    config: &TracingConfig,
+    req: &ServiceRequest,
+    attributes: &mut Vec<KeyValue>,
+) {
+    let Some(pattern) = req.match_pattern() else {
+        return;
+    };
+    let mut path = Path::new(Url::new(req.uri().clone()));
+    if !ResourceDef::new(pattern).capture_match_info(&mut path) {
+        return;
+    }
+    for (name, value) in path.iter() {
+        if config.path_param_names.is_empty() || config.path_param_names.iter().any(|n| n == name) {
+            attributes.push(KeyValue::new(
+                format!("http.route.param.{name}"),
+                value.to_string(),
+            ));
+        }
+    }
+}
+
+fn truncate_attributes(config: &TracingConfig, attributes: &mut [KeyValue]) {
+    if let Some(max_length) = config.max_attribute_length {
+        for kv in attributes {
+            truncate_value(&mut kv.value, max_length);
+        }
+    }
+}
+
+/// The extension of type `T` formatted with its `Display` implementation.
+fn extension_value<T: fmt::Display + 'static>(extensions: &Extensions) -> Option<String> {
+    extensions.get::<T>().map(ToString::to_string)
+}
+
+/// Cut string values longer than `max_length` chars, marking them with an
+/// ellipsis.
+fn truncate_value(value: &mut Value, max_length: usize) {
+    if let Value::String(string) = value {
+        if let Some((end, _)) = string.as_str().char_indices().nth(max_length) {
+            *value = format!("{}…", &string.as_str()[..end]).into();
+        }
+    }
+}
+
+fn end_span(span: &SpanRef<'_>, config: &TracingConfig, start: Instant) {
+    if config.duration_attribute {
+        span.set_attribute(KeyValue::new(
+            HTTP_SERVER_DURATION_MS,
+            start.elapsed().as_secs_f64() * 1000.0,
+        ));
+    }
+    span.end();
+}
+
+// record an exception event following the semantic conventions:
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/exceptions/exceptions-spans.md
+fn record_exception(span: &SpanRef<'_>, exception_type: &'static str, message: String) {
+    span.add_event(
+        "exception",
+        vec![
+            KeyValue::new(EXCEPTION_TYPE, exception_type),
+            KeyValue::new(EXCEPTION_MESSAGE, message),
+        ],
+    );
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// The service and method of gRPC requests, e.g. `/helloworld.Greeter/SayHello`.
+fn grpc_method(req: &ServiceRequest) -> Option<(String, String)> {
+    let content_type = req.headers().get(CONTENT_TYPE)?.as_bytes();
+    if !content_type.starts_with(b"application/grpc") {
+        return None;
+    }
+
+    let (service, method) = req.path().strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+
+    Some((service.to_string(), method.to_string()))
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<i64> {
+    headers.get("grpc-status")?.to_str().ok()?.parse().ok()
+}
+
+// gRPC status codes reported as server span errors following the semantic conventions:
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/rpc/grpc.md#grpc-status
+fn is_grpc_server_error(status: i64) -> bool {
+    // UNKNOWN, DEADLINE_EXCEEDED, UNIMPLEMENTED, INTERNAL, UNAVAILABLE, DATA_LOSS
+    matches!(status, 2 | 4 | 12 | 13 | 14 | 15)
+}
+
+fn is_truthy_header(headers: &HeaderMap, header: &HeaderName) -> bool {
+    headers.get(header).is_some_and(|value| {
+        let value = value.as_bytes().trim_ascii();
+        value == b"1" || value.eq_ignore_ascii_case(b"true")
+    })
+}
+
+fn is_websocket_upgrade(status: StatusCode, headers: &HeaderMap) -> bool {
+    status == StatusCode::SWITCHING_PROTOCOLS
+        && headers
+            .get(UPGRADE)
+            .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+fn default_status_mapper(status: StatusCode) -> Option<Status> {
+    if status.is_server_error() {
+        Some(Status::error(
+            status
+                .canonical_reason()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Request payload that counts the bytes read from it.
+struct CountingPayload {
+    payload: Payload,
+    size: Rc<Cell<i64>>,
+}
+
+impl Stream for CountingPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let item = ready!(Pin::new(&mut self.payload).poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            self.size.set(self.size.get() + chunk.len() as i64);
+        }
+        Poll::Ready(item)
+    }
+}
+
 struct RequestHeaderCarrier<'a> {
     headers: &'a HeaderMap,
 }
@@ -246,3 +2094,150 @@ impl Extractor for RequestHeaderCarrier<'_> {
         self.headers.keys().map(|header| header.as_str()).collect()
     }
 }
+
+/// Answers every propagation field with the value of a link header.
+struct LinkHeaderCarrier<'a> {
+    value: &'a str,
+}
+
+impl Extractor for LinkHeaderCarrier<'_> {
+    fn get(&self, _key: &str) -> Option<&str> {
+        Some(self.value)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+struct ResponseHeaderCarrier<'a> {
+    headers: &'a mut HeaderMap,
+}
+
+impl<'a> ResponseHeaderCarrier<'a> {
+    fn new(headers: &'a mut HeaderMap) -> Self {
+        ResponseHeaderCarrier { headers }
+    }
+}
+
+impl Injector for ResponseHeaderCarrier<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.headers.insert(name, value);
+        } else {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                name: "invalid_propagation_header",
+                target: env!("CARGO_PKG_NAME"),
+                name = "invalid_propagation_header",
+                key = key,
+                ""
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+    use futures_util::future::BoxFuture;
+    use opentelemetry::trace::TraceId;
+    use opentelemetry_sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::ShouldSample,
+    };
+    use opentelemetry_semantic_conventions::trace::URL_PATH;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    struct TestExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for TestExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(ok(()))
+        }
+    }
+
+    /// Drops spans of health checks, which requires `url.path` at span creation.
+    #[derive(Clone, Debug)]
+    struct HealthCheckSampler;
+
+    impl ShouldSample for HealthCheckSampler {
+        fn should_sample(
+            &self,
+            _parent_context: Option<&Context>,
+            _trace_id: TraceId,
+            _name: &str,
+            _span_kind: &SpanKind,
+            attributes: &[KeyValue],
+            _links: &[Link],
+        ) -> SamplingResult {
+            let health_check = attributes
+                .iter()
+                .any(|kv| kv.key.as_str() == URL_PATH && kv.value.as_str() == "/healthz");
+            SamplingResult {
+                decision: if health_check {
+                    SamplingDecision::Drop
+                } else {
+                    SamplingDecision::RecordAndSample
+                },
+                attributes: Vec::new(),
+                trace_state: Default::default(),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn samplers_see_request_attributes() {
+        let exporter = TestExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .with_sampler(HealthCheckSampler)
+            .build();
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestTracing::new().with_tracer_provider(provider.clone()))
+                .route("/healthz", web::get().to(|| async { "ok" }))
+                .route("/users", web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        for uri in ["/healthz", "/users"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let spans = exporter.0.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "/users");
+    }
+
+    #[actix_web::test]
+    async fn path_params_are_recorded_for_app_middleware() {
+        let exporter = TestExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    RequestTracing::new()
+                        .with_tracer_provider(provider.clone())
+                        .with_path_params(true),
+                )
+                .route("/users/{id}", web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/users/42").to_request();
+        test::call_service(&app, req).await;
+
+        let spans = exporter.0.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0]
+            .attributes
+            .contains(&KeyValue::new("http.route.param.id", "42")));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{
+    middleware::get_scope,
+    util::{client_span_status, client_trace_attributes, protocol_version},
+};
+use actix_web::http::{Method, Uri, Version};
+use opentelemetry::{
+    global,
+    propagation::Injector,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_semantic_conventions::trace::{
+    ERROR_TYPE, HTTP_RESPONSE_STATUS_CODE, NETWORK_PROTOCOL_VERSION, USER_AGENT_ORIGINAL,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+    RequestBuilder, Response,
+};
+use std::{mem, str::FromStr};
+
+/// A wrapper for a [reqwest::RequestBuilder].
+#[derive(Debug)]
+pub struct InstrumentedReqwestRequest {
+    cx: Context,
+    attrs: Vec<KeyValue>,
+    request: RequestBuilder,
+}
+
+/// OpenTelemetry extensions for [reqwest::RequestBuilder].
+pub trait ReqwestClientExt {
+    /// Trace a [reqwest] request using the current context.
+    ///
+    /// Example:
+    /// ```no_run
+    /// use actix_web_opentelemetry::ReqwestClientExt;
+    ///
+    /// async fn execute_request(client: &reqwest::Client) -> Result<(), reqwest::Error> {
+    ///     let res = client.get("http://localhost:8080")
+    ///         // Add `trace_request` before `send` to any reqwest request to add instrumentation
+    ///         .trace_request()
+    ///         .send()
+    ///         .await?;
+    ///
+    ///     println!("Response: {:?}", res);
+    ///     Ok(())
+    /// }
+    /// ```
+    fn trace_request(self) -> InstrumentedReqwestRequest
+    where
+        Self: Sized,
+    {
+        self.trace_request_with_context(Context::current())
+    }
+
+    /// Trace a [reqwest] request using the given span context.
+    fn trace_request_with_context(self, cx: Context) -> InstrumentedReqwestRequest;
+}
+
+impl ReqwestClientExt for RequestBuilder {
+    fn trace_request_with_context(self, cx: Context) -> InstrumentedReqwestRequest {
+        InstrumentedReqwestRequest {
+            cx,
+            attrs: Vec::with_capacity(8),
+            request: self,
+        }
+    }
+}
+
+impl InstrumentedReqwestRequest {
+    /// Add additional attributes to the instrumented span for a given request.
+    ///
+    /// The standard otel attributes will still be tracked.
+    pub fn with_attributes(
+        mut self,
+        attrs: impl IntoIterator<Item = KeyValue>,
+    ) -> InstrumentedReqwestRequest {
+        self.attrs.extend(attrs);
+        self
+    }
+
+    /// Send the traced request.
+    ///
+    /// Requests that fail to build are returned as errors without a span.
+    pub async fn send(mut self) -> Result<Response, reqwest::Error> {
+        let (client, request) = self.request.build_split();
+        let mut request = request?;
+        let tracer = global::tracer_with_scope(get_scope());
+
+        // Client attributes
+        // https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/http/http-spans.md#http-client
+        // reqwest uses `http` 1.x types, convert them to the ones used by actix
+        let method =
+            Method::from_bytes(request.method().as_str().as_bytes()).unwrap_or(Method::GET);
+        let uri = Uri::try_from(request.url().as_str()).unwrap_or_default();
+        let host = request.url().host_str().unwrap_or("unknown").to_string();
+        self.attrs
+            .extend(client_trace_attributes(&method, &uri, false, false));
+        if let Some(user_agent) = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+        {
+            self.attrs
+                .push(KeyValue::new(USER_AGENT_ORIGINAL, user_agent.to_string()));
+        }
+
+        let span = tracer
+            .span_builder(format!("{} {}", request.method(), host))
+            .with_kind(SpanKind::Client)
+            .with_attributes(mem::take(&mut self.attrs))
+            .start_with_context(&tracer, &self.cx);
+        let cx = self.cx.with_span(span);
+
+        global::get_text_map_propagator(|injector| {
+            injector.inject_context(&cx, &mut ReqwestCarrier::new(request.headers_mut()));
+        });
+
+        let span = cx.span();
+        match client.execute(request).await {
+            Ok(response) => {
+                span.set_status(client_span_status(response.status().as_u16()));
+                span.set_attribute(KeyValue::new(
+                    HTTP_RESPONSE_STATUS_CODE,
+                    response.status().as_u16() as i64,
+                ));
+                span.set_attribute(KeyValue::new(
+                    NETWORK_PROTOCOL_VERSION,
+                    protocol_version(http_version(response.version())),
+                ));
+                span.end();
+                Ok(response)
+            }
+            Err(err) => {
+                span.set_attribute(KeyValue::new(ERROR_TYPE, error_type(&err)));
+                span.set_status(Status::error(format!("{:?}", err)));
+                span.end();
+                Err(err)
+            }
+        }
+    }
+}
+
+fn http_version(version: reqwest::Version) -> Version {
+    match version {
+        reqwest::Version::HTTP_09 => Version::HTTP_09,
+        reqwest::Version::HTTP_10 => Version::HTTP_10,
+        reqwest::Version::HTTP_2 => Version::HTTP_2,
+        reqwest::Version::HTTP_3 => Version::HTTP_3,
+        _ => Version::HTTP_11,
+    }
+}
+
+// classify send errors for the `error.type` attribute:
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.23.0/docs/attributes-registry/error.md
+fn error_type(err: &reqwest::Error) -> &'static str {
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else if err.is_redirect() {
+        "redirect"
+    } else if err.is_builder() {
+        "invalid_url"
+    } else if err.is_decode() {
+        "invalid_response"
+    } else if err.is_body() {
+        "body"
+    } else if err.is_request() {
+        "send"
+    } else {
+        "_OTHER"
+    }
+}
+
+struct ReqwestCarrier<'a> {
+    headers: &'a mut HeaderMap,
+}
+
+impl<'a> ReqwestCarrier<'a> {
+    fn new(headers: &'a mut HeaderMap) -> Self {
+        ReqwestCarrier { headers }
+    }
+}
+
+impl Injector for ReqwestCarrier<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_str(key), HeaderValue::from_str(&value)) {
+            self.headers.insert(name, value);
+        } else {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                name: "invalid_propagation_header",
+                target: env!("CARGO_PKG_NAME"),
+                name = "invalid_propagation_header",
+                key = key,
+                ""
+            );
+        }
+    }
+}
@@ -1,26 +1,34 @@
-use actix_http::header::{self, CONTENT_LENGTH};
+use actix_http::header::{self, HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH};
 use actix_web::{
     dev::ServiceRequest,
-    http::{Method, Version},
+    http::{Method, Uri, Version},
 };
-use opentelemetry::{KeyValue, Value};
+use opentelemetry::trace::Status;
+use opentelemetry::{Array, Key, KeyValue, StringValue, Value};
 use opentelemetry_semantic_conventions::trace::{
-    CLIENT_ADDRESS, NETWORK_PEER_ADDRESS, MESSAGING_MESSAGE_BODY_SIZE, HTTP_REQUEST_METHOD, HTTP_ROUTE,
-    NETWORK_PROTOCOL_VERSION, SERVER_ADDRESS, SERVER_PORT, URL_PATH, URL_QUERY, URL_SCHEME,
-    USER_AGENT_ORIGINAL,
+    CLIENT_ADDRESS, HTTP_REQUEST_METHOD, HTTP_ROUTE, MESSAGING_MESSAGE_BODY_SIZE,
+    NETWORK_PEER_ADDRESS, NETWORK_PROTOCOL_NAME, NETWORK_PROTOCOL_VERSION, NETWORK_TRANSPORT,
+    SERVER_ADDRESS, SERVER_PORT, URL_FULL, URL_PATH, URL_QUERY, URL_SCHEME, USER_AGENT_ORIGINAL,
 };
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const HTTP_ROUTE_NAME: &str = "http.route.name";
 
-#[cfg(feature = "awc")]
 #[inline]
-pub(super) fn http_url(uri: &actix_web::http::Uri) -> String {
+pub(super) fn http_url(uri: &Uri, sort_query: bool) -> String {
     let scheme = uri.scheme().map(|s| s.as_str()).unwrap_or_default();
     let host = uri.host().unwrap_or_default();
     let path = uri.path();
     let port = uri.port_u16().filter(|&p| p != 80 && p != 443);
-    let (query, query_delimiter) = if let Some(query) = uri.query() {
-        (query, "?")
-    } else {
-        ("", "")
+    let (query, query_delimiter) = match uri.query() {
+        Some(query) if sort_query => {
+            let mut params = query.split('&').collect::<Vec<_>>();
+            params.sort_unstable();
+            (Cow::Owned(params.join("&")), "?")
+        }
+        Some(query) => (query.into(), "?"),
+        None => ("".into(), ""),
     };
 
     if let Some(port) = port {
@@ -30,6 +38,57 @@ pub(super) fn http_url(uri: &actix_web::http::Uri) -> String {
     }
 }
 
+// convert http status code to span status following the rules described by the spec:
+// https://github.com/open-telemetry/opentelemetry-specification/blob/main/specification/trace/semantic_conventions/http.md#status
+pub(super) fn client_span_status(status_code: u16) -> Status {
+    match status_code {
+        100..=399 => Status::Unset,
+        // since we are the client, we MUST treat 4xx as error
+        400..=599 => Status::error("Unexpected status code"),
+        code => Status::error(format!("Invalid HTTP status code {}", code)),
+    }
+}
+
+/// Create the client span attributes for a request to the given uri.
+///
+/// `server.port` is only recorded for non default ports unless
+/// `default_server_port` is set.
+// https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/http/http-spans.md#http-client
+pub(super) fn client_trace_attributes(
+    method: &Method,
+    uri: &Uri,
+    sort_query: bool,
+    default_server_port: bool,
+) -> Vec<KeyValue> {
+    let mut attributes = Vec::with_capacity(5);
+    attributes.push(KeyValue::new(
+        SERVER_ADDRESS,
+        uri.host()
+            .map(|host| Cow::Owned(host.to_string()))
+            .unwrap_or(Cow::Borrowed("unknown")),
+    ));
+    attributes.push(KeyValue::new(HTTP_REQUEST_METHOD, http_method_str(method)));
+    attributes.push(KeyValue::new(URL_FULL, http_url(uri, sort_query)));
+    if let Some(scheme) = uri.scheme_str() {
+        attributes.push(KeyValue::new(URL_SCHEME, url_scheme(scheme)));
+    }
+    if default_server_port {
+        let port = uri.port_u16().or_else(|| match uri.scheme_str() {
+            Some("http") => Some(80),
+            Some("https") => Some(443),
+            _ => None,
+        });
+        if let Some(port) = port {
+            attributes.push(KeyValue::new(SERVER_PORT, port as i64));
+        }
+    } else if let Some(port) = uri.port_u16() {
+        if port != 80 && port != 443 {
+            attributes.push(KeyValue::new(SERVER_PORT, port as i64));
+        }
+    }
+    attributes
+}
+
 #[inline]
 pub(super) fn http_method_str(method: &Method) -> Value {
     match method {
@@ -58,6 +117,16 @@ pub(super) fn protocol_version(version: Version) -> Value {
     }
 }
 
+/// The transport protocol a given http version runs over, HTTP/3 uses QUIC
+#[inline]
+fn network_transport(version: Version) -> Option<&'static str> {
+    match version {
+        Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 | Version::HTTP_2 => Some("tcp"),
+        Version::HTTP_3 => Some("quic"),
+        _ => None,
+    }
+}
+
 #[inline]
 pub(super) fn url_scheme(scheme: &str) -> Value {
     match scheme {
@@ -67,60 +136,313 @@ pub(super) fn url_scheme(scheme: &str) -> Value {
     }
 }
 
-pub(super) fn trace_attributes_from_request(
+/// The scheme used by the original client according to the `Forwarded` or
+/// `X-Forwarded-Proto` headers set by proxies.
+///
+/// The first (client-most) value is used if a header lists several hops.
+pub(super) fn forwarded_proto(headers: &HeaderMap) -> Option<String> {
+    let forwarded = headers
+        .get(header::FORWARDED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .next()?
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("proto"))
+                .map(|(_, proto)| proto.trim().trim_matches('"'))
+        });
+    let proto = forwarded.or_else(|| {
+        headers
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+    })?;
+
+    (!proto.is_empty()).then(|| proto.to_ascii_lowercase())
+}
+
+/// The client address in the `X-Forwarded-For` headers, `depth` entries from
+/// the right.
+///
+/// Returns `None` if the headers have fewer entries.
+pub(super) fn forwarded_for(headers: &HeaderMap, depth: usize) -> Option<String> {
+    let entries = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let entry = entries.get(entries.len().checked_sub(depth)?)?;
+
+    (!entry.is_empty()).then(|| entry.to_string())
+}
+
+/// Mask the host part of an ip address, zeroing the last octet of IPv4 and the
+/// last 80 bits of IPv6 addresses.
+///
+/// Ports are dropped from socket addresses, other values are returned as is.
+pub(super) fn anonymize_ip(addr: &str) -> String {
+    let ip = addr
+        .parse::<IpAddr>()
+        .or_else(|_| addr.parse::<SocketAddr>().map(|socket| socket.ip()));
+    match ip {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+        }
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// Split a `Host` header value into the host and the port, if valid.
+///
+/// Bracketed IPv6 literals such as `[::1]:8080` keep their brackets.
+fn split_host_port(host: &str) -> (&str, Option<i64>) {
+    let (host, port) = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => {
+                let (host, rest) = host.split_at(end + 1);
+                (host, rest.strip_prefix(':'))
+            }
+            None => (host, None),
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host, None),
+        }
+    };
+
+    (host, port.and_then(|port| port.parse().ok()))
+}
+
+/// Normalize header names into the attribute keys they are recorded under.
+///
+/// Keys follow the `<prefix>.<key>` convention, where `<key>` is the lowercase
+/// header name with `-` replaced by `_`. Invalid header names are skipped.
+pub(super) fn header_attribute_keys(
+    prefix: &str,
+    names: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Vec<(HeaderName, Key)> {
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let name = HeaderName::try_from(name.as_ref()).ok()?;
+            let key = Key::from(format!("{prefix}.{}", name.as_str().replace('-', "_")));
+            Some((name, key))
+        })
+        .collect()
+}
+
+/// Redacts header values before they are recorded as attributes.
+///
+/// Returning `None` drops the value.
+pub(super) type HeaderRedactor = fn(&HeaderName, &HeaderValue) -> Option<String>;
+
+/// Record header values unchanged.
+pub(super) fn default_header_redactor(_name: &HeaderName, value: &HeaderValue) -> Option<String> {
+    value.to_str().ok().map(ToString::to_string)
+}
+
+/// Headers carrying credentials, which are never recorded.
+fn is_credential_header(name: &HeaderName) -> bool {
+    [
+        header::AUTHORIZATION,
+        header::COOKIE,
+        header::SET_COOKIE,
+        header::PROXY_AUTHORIZATION,
+    ]
+    .contains(name)
+}
+
+/// Record the configured headers that are present in `headers`.
+///
+/// Each header is recorded as a string array to preserve multiple values.
+/// Credential headers are dropped before `redactor` sees them.
+pub(super) fn header_attributes(
+    headers: &HeaderMap,
+    captured: &[(HeaderName, Key)],
+    redactor: HeaderRedactor,
+    attributes: &mut Vec<KeyValue>,
+) {
+    for (name, key) in captured {
+        if is_credential_header(name) {
+            continue;
+        }
+        let values: Vec<StringValue> = headers
+            .get_all(name)
+            .filter_map(|value| redactor(name, value))
+            .map(Into::into)
+            .collect();
+        if !values.is_empty() {
+            attributes.push(KeyValue::new(
+                key.clone(),
+                Value::Array(Array::String(values)),
+            ));
+        }
+    }
+}
+
+/// Create server span attributes for the given request
+///
+/// These are the standard attributes recorded by [`RequestTracing`], for use
+/// in custom middleware. The name of the matched route, if set with
+/// [`actix_web::Resource::name`], is recorded as `http.route.name`.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::test::TestRequest;
+/// use actix_web_opentelemetry::trace_attributes_from_request;
+/// use opentelemetry::{KeyValue, Value};
+///
+/// fn attribute(attributes: &[KeyValue], key: &str) -> Option<Value> {
+///     attributes
+///         .iter()
+///         .find(|kv| kv.key.as_str() == key)
+///         .map(|kv| kv.value.clone())
+/// }
+///
+/// let req = TestRequest::default()
+///     .insert_header(("host", "[2001:db8::1]:9000"))
+///     .to_srv_request();
+/// let attributes = trace_attributes_from_request(&req, "/");
+/// assert_eq!(attribute(&attributes, "server.address"), Some("[2001:db8::1]".into()));
+/// assert_eq!(attribute(&attributes, "server.port"), Some(9000.into()));
+///
+/// # let req = TestRequest::default()
+/// #     .insert_header(("host", "example.com:8443"))
+/// #     .to_srv_request();
+/// # let attributes = trace_attributes_from_request(&req, "/");
+/// # assert_eq!(attribute(&attributes, "server.address"), Some("example.com".into()));
+/// # assert_eq!(attribute(&attributes, "server.port"), Some(8443.into()));
+/// #
+/// # let req = TestRequest::default()
+/// #     .insert_header(("host", "example.com"))
+/// #     .to_srv_request();
+/// # let attributes = trace_attributes_from_request(&req, "/");
+/// # assert_eq!(attribute(&attributes, "server.address"), Some("example.com".into()));
+/// # assert_eq!(attribute(&attributes, "server.port"), None);
+/// # assert_eq!(attribute(&attributes, "network.transport"), Some("tcp".into()));
+/// #
+/// # let req = TestRequest::default()
+/// #     .version(actix_web::http::Version::HTTP_3)
+/// #     .to_srv_request();
+/// # let attributes = trace_attributes_from_request(&req, "/");
+/// # assert_eq!(attribute(&attributes, "network.transport"), Some("quic".into()));
+/// ```
+///
+/// [`RequestTracing`]: crate::RequestTracing
+pub fn trace_attributes_from_request(req: &ServiceRequest, http_route: &str) -> Vec<KeyValue> {
+    server_trace_attributes(req, http_route, &[], false, false)
+}
+
+/// Create the server span attributes for the given request, skipping the
+/// `excluded` attributes without building them, optionally anonymizing
+/// client addresses with [`anonymize_ip`] and optionally taking the server
+/// address from the literal `Host` header.
+pub(super) fn server_trace_attributes(
     req: &ServiceRequest,
     http_route: &str,
+    excluded: &[Key],
+    anonymize: bool,
+    host_header: bool,
 ) -> Vec<KeyValue> {
+    let address = |addr: &str| {
+        if anonymize {
+            anonymize_ip(addr)
+        } else {
+            addr.to_string()
+        }
+    };
+    let include = |key: &str| !excluded.iter().any(|k| k.as_str() == key);
     let conn_info = req.connection_info();
     let remote_addr = conn_info.realip_remote_addr();
 
-    let mut attributes = Vec::with_capacity(14);
+    let mut attributes = Vec::with_capacity(16);
 
     // Server attrs
     // <https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/http/http-spans.md#http-server>
-    attributes.push(KeyValue::new(HTTP_ROUTE, http_route.to_owned()));
-    if let Some(remote) = remote_addr {
-        attributes.push(KeyValue::new(CLIENT_ADDRESS, remote.to_string()));
+    if include(HTTP_ROUTE) {
+        attributes.push(KeyValue::new(HTTP_ROUTE, http_route.to_owned()));
+    }
+    if include(HTTP_ROUTE_NAME) {
+        if let Some(route_name) = req.match_name() {
+            attributes.push(KeyValue::new(HTTP_ROUTE_NAME, route_name.to_string()));
+        }
     }
-    if let Some(peer_addr) = req.peer_addr().map(|socket| socket.ip().to_string()) {
-        if Some(peer_addr.as_str()) != remote_addr {
-            // Client is going through a proxy
-            attributes.push(KeyValue::new(NETWORK_PEER_ADDRESS, peer_addr));
+    if include(CLIENT_ADDRESS) {
+        if let Some(remote) = remote_addr {
+            attributes.push(KeyValue::new(CLIENT_ADDRESS, address(remote)));
         }
     }
-    let mut host_parts = conn_info.host().split_terminator(':');
-    if let Some(host) = host_parts.next() {
+    if include(NETWORK_PEER_ADDRESS) {
+        if let Some(peer_addr) = req.peer_addr().map(|socket| socket.ip().to_string()) {
+            if Some(peer_addr.as_str()) != remote_addr {
+                // Client is going through a proxy
+                attributes.push(KeyValue::new(NETWORK_PEER_ADDRESS, address(&peer_addr)));
+            }
+        }
+    }
+    let host = host_header
+        .then(|| req.headers().get(header::HOST))
+        .flatten()
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_else(|| conn_info.host());
+    let (host, port) = split_host_port(host);
+    if !host.is_empty() && include(SERVER_ADDRESS) {
         attributes.push(KeyValue::new(SERVER_ADDRESS, host.to_string()));
     }
-    if let Some(port) = host_parts.next().and_then(|port| port.parse::<i64>().ok()) {
-        if port != 80 && port != 443 {
+    if let Some(port) = port {
+        if port != 80 && port != 443 && include(SERVER_PORT) {
             attributes.push(KeyValue::new(SERVER_PORT, port));
         }
     }
     if let Some(path_query) = req.uri().path_and_query() {
-        if path_query.path() != "/" {
+        if path_query.path() != "/" && include(URL_PATH) {
             attributes.push(KeyValue::new(URL_PATH, path_query.path().to_string()));
         }
-        if let Some(query) = path_query.query() {
+        if let Some(query) = path_query.query().filter(|_| include(URL_QUERY)) {
             attributes.push(KeyValue::new(URL_QUERY, query.to_string()));
         }
     }
-    attributes.push(KeyValue::new(URL_SCHEME, url_scheme(conn_info.scheme())));
+    if include(URL_SCHEME) {
+        attributes.push(KeyValue::new(URL_SCHEME, url_scheme(conn_info.scheme())));
+    }
 
     // Common attrs
     // <https://github.com/open-telemetry/semantic-conventions/blob/v1.21.0/docs/http/http-spans.md#common-attributes>
-    attributes.push(KeyValue::new(
-        HTTP_REQUEST_METHOD,
-        http_method_str(req.method()),
-    ));
-    attributes.push(KeyValue::new(
-        NETWORK_PROTOCOL_VERSION,
-        protocol_version(req.version()),
-    ));
+    if include(HTTP_REQUEST_METHOD) {
+        attributes.push(KeyValue::new(
+            HTTP_REQUEST_METHOD,
+            http_method_str(req.method()),
+        ));
+    }
+    if include(NETWORK_PROTOCOL_NAME) {
+        attributes.push(KeyValue::new(NETWORK_PROTOCOL_NAME, "http"));
+    }
+    if include(NETWORK_PROTOCOL_VERSION) {
+        attributes.push(KeyValue::new(
+            NETWORK_PROTOCOL_VERSION,
+            protocol_version(req.version()),
+        ));
+    }
+    if let Some(transport) = network_transport(req.version()).filter(|_| include(NETWORK_TRANSPORT))
+    {
+        attributes.push(KeyValue::new(NETWORK_TRANSPORT, transport));
+    }
 
     if let Some(content_length) = req
         .headers()
         .get(CONTENT_LENGTH)
+        .filter(|_| include(MESSAGING_MESSAGE_BODY_SIZE))
         .and_then(|len| len.to_str().ok().and_then(|s| s.parse::<i64>().ok()))
         .filter(|&len| len > 0)
     {
@@ -130,6 +452,7 @@ pub(super) fn trace_attributes_from_request(
     if let Some(user_agent) = req
         .headers()
         .get(header::USER_AGENT)
+        .filter(|_| include(USER_AGENT_ORIGINAL))
         .and_then(|s| s.to_str().ok())
     {
         attributes.push(KeyValue::new(USER_AGENT_ORIGINAL, user_agent.to_string()));
@@ -146,25 +469,65 @@ pub fn metrics_attributes_from_request(
 ) -> Vec<KeyValue> {
     let conn_info = req.connection_info();
 
-    let mut attributes = Vec::with_capacity(7);
+    let mut attributes = Vec::with_capacity(8);
     attributes.push(KeyValue::new(HTTP_ROUTE, http_route));
     attributes.push(KeyValue::new(
         HTTP_REQUEST_METHOD,
         http_method_str(req.method()),
     ));
+    attributes.push(KeyValue::new(NETWORK_PROTOCOL_NAME, "http"));
     attributes.push(KeyValue::new(
         NETWORK_PROTOCOL_VERSION,
         protocol_version(req.version()),
     ));
 
-    let mut host_parts = conn_info.host().split_terminator(':');
-    if let Some(host) = host_parts.next() {
+    let (host, port) = split_host_port(conn_info.host());
+    if !host.is_empty() {
         attributes.push(KeyValue::new(SERVER_ADDRESS, host.to_string()));
     }
-    if let Some(port) = host_parts.next().and_then(|port| port.parse::<i64>().ok()) {
+    if let Some(port) = port {
         attributes.push(KeyValue::new(SERVER_PORT, port))
     }
     attributes.push(KeyValue::new(URL_SCHEME, url_scheme(conn_info.scheme())));
 
     attributes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_attributes_drop_credentials_with_custom_redactor() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=secret"));
+        headers.insert(
+            HeaderName::from_static("x-tenant-id"),
+            HeaderValue::from_static("acme"),
+        );
+        let captured = header_attribute_keys(
+            "http.request.header",
+            ["authorization", "cookie", "x-tenant-id"],
+        );
+
+        let mut attributes = Vec::new();
+        header_attributes(
+            &headers,
+            &captured,
+            |_, value| value.to_str().ok().map(ToString::to_string),
+            &mut attributes,
+        );
+
+        assert_eq!(
+            attributes,
+            vec![KeyValue::new(
+                "http.request.header.x_tenant_id",
+                Value::Array(Array::String(vec!["acme".into()])),
+            )]
+        );
+    }
+}